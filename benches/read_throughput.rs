@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use renogy::{Battery, MockBattery};
+use tokio::sync::Mutex;
+
+const ITERATIONS: u32 = 1000;
+
+/// Run `f` ITERATIONS times and print the transactions and mean time per call
+async fn bench<F, Fut, T>(name: &str, port: &Mutex<renogy::Port>, mut f: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = renogy::Result<T>>,
+{
+    let first = port.lock().await.sequence();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        if let Err(e) = f().await {
+            println!("{}: failed: {:?}", name, e);
+            return;
+        }
+    }
+    let mean = start.elapsed() / ITERATIONS;
+    let transactions = (port.lock().await.sequence() - first) as f64 / ITERATIONS as f64;
+    println!("{:<16} {:>6.1} transactions {:>10.3} us", name, transactions, mean.as_secs_f64() * 1e6);
+}
+
+#[tokio::main]
+async fn main() {
+    let mock = MockBattery::rbt100();
+    let mut port = mock.port();
+    // Without the inter-frame delay, the times are the crate's own overhead per call
+    port.set_frame_delay(Duration::ZERO);
+    let port = Arc::new(Mutex::new(port));
+    let battery = Battery::new(port.clone(), 247);
+
+    bench("read_all", &port, || battery.read_all()).await;
+    bench("status", &port, || battery.status()).await;
+    bench("cell_voltages_4", &port, || battery.cell_voltages_4()).await;
+    bench("voltage", &port, || battery.voltage()).await;
+}
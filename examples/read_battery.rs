@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use renogy::Battery;
+use renogy::{Battery, Port};
 use tokio::sync::Mutex;
 
 
@@ -13,26 +13,25 @@ struct Args {
 }
 
 
-fn open_battery(port: &str, addr: u8) -> Battery {
-    let port = match renogy::Port::new(port) {
+fn open_port(port: &str) -> Arc<Mutex<Port>> {
+    match Port::open_shared(port) {
         Ok(p) => p,
         Err(e) => {
             println!("Could not open port {}: {:?}", port, e);
             std::process::exit(-1);
         }
-    };
-    let port = Arc::new(Mutex::new(port));
-
-    Battery::new(port.clone(), addr)
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    // Read status from two batteries with IDs 246 and 247
-    let battery1 = open_battery(&args.port, 246);
-    let battery2 = open_battery(&args.port, 247);
+    // Read status from two batteries with IDs 246 and 247. Both are on the same bus, so they
+    // share one port.
+    let port = open_port(&args.port);
+    let battery1 = Battery::new(port.clone(), 246);
+    let battery2 = Battery::new(port.clone(), 247);
 
     println!("Reading 246");
     match battery1.read_all().await {
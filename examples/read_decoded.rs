@@ -0,0 +1,56 @@
+use renogy::{Battery, Port};
+
+use clap::Parser;
+
+#[derive(Debug, Parser)]
+struct Args {
+    #[arg(short, long)]
+    port: String,
+    #[arg(short, long, default_value_t = 247)]
+    id: u8,
+}
+
+/// Alarm flags packed into the status1 register (0x13f2)
+struct Alarms {
+    short_circuit: bool,
+    over_current: bool,
+    over_voltage: bool,
+    under_voltage: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let port = match Port::open_shared(&args.port) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Could not open port {}: {:?}", args.port, e);
+            std::process::exit(-1);
+        }
+    };
+    let battery = Battery::new(port, args.id);
+
+    // Pick a few bits out of the status word by hand
+    let alarms = battery
+        .read_decoded(0x13f2, 1, |words| {
+            let word = words.first().copied().unwrap_or(0);
+            Alarms {
+                short_circuit: word & (1 << 15) != 0,
+                over_current: word & (1 << 5 | 1 << 6 | 1 << 10 | 1 << 11) != 0,
+                over_voltage: word & (1 << 7 | 1 << 9) != 0,
+                under_voltage: word & (1 << 0 | 1 << 8) != 0,
+            }
+        })
+        .await;
+
+    match alarms {
+        Ok(alarms) => {
+            println!("Short circuit: {}", alarms.short_circuit);
+            println!("Over current: {}", alarms.over_current);
+            println!("Over voltage: {}", alarms.over_voltage);
+            println!("Under voltage: {}", alarms.under_voltage);
+        }
+        Err(e) => println!("Error: {:?}", e),
+    }
+}
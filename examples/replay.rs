@@ -0,0 +1,27 @@
+use clap::Parser;
+
+/// Decode the battery state from a transaction log written with a transaction hook
+#[derive(Debug, Parser)]
+struct Args {
+    /// The log file, one transaction per line
+    log: std::path::PathBuf,
+    #[arg(short, long, default_value_t = 247)]
+    id: u8,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let log = match std::fs::read_to_string(&args.log) {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Could not read {}: {}", args.log.display(), e);
+            std::process::exit(-1);
+        }
+    };
+    match renogy::replay(&log, args.id).await {
+        Ok(state) => println!("State: {:?}", state),
+        Err(e) => println!("Error: {:?}", e),
+    }
+}
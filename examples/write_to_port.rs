@@ -1,6 +1,4 @@
-use std::{sync::Arc, time::Duration};
-
-use std::sync::Mutex;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -16,6 +14,7 @@ async fn main() {
         std::thread::sleep(Duration::from_millis(1000));
         let mut buf = [0; 128];
         let bytes_read = port.read(&mut buf).unwrap();
+        println!("read {:?}", &buf[..bytes_read]);
 
     }
 }
@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A source of the current time, so time dependent features can be tested without waiting
+///
+/// `Battery` takes its timestamps from one, see `Battery::with_clock`. Port rate limiting and
+/// interval timers run on tokio's clock instead, which tests can control with
+/// `tokio::time::pause` and `tokio::time::advance`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real system clock, used by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when told to
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    /// A clock stopped at the present time
+    pub fn new() -> Self {
+        Self { now: Mutex::new(Instant::now()) }
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
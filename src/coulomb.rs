@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::{Battery, Error, Result};
+
+/// Tracks state of charge by integrating battery current over time
+///
+/// `BatteryState::state_of_charge` drifts along with the BMS's charge estimate. Feeding regular
+/// `Battery::current` readings into a counter seeded from a known charge gives an independent
+/// estimate.
+#[derive(Clone, Copy, Debug)]
+pub struct CoulombCounter {
+    charge: f64,
+    capacity: f64,
+}
+
+impl CoulombCounter {
+    /// Create a counter starting from `initial_charge` Ah in a pack of `capacity` Ah
+    ///
+    /// Fails with `Error::InvalidInput` unless `capacity` is positive and finite and
+    /// `initial_charge` is a number. The initial charge is clamped to the capacity.
+    pub fn new(initial_charge: f64, capacity: f64) -> Result<Self> {
+        if !(capacity.is_finite() && capacity > 0.0) {
+            return Err(Error::InvalidInput(format!("capacity {} Ah is not positive", capacity)));
+        }
+        if initial_charge.is_nan() {
+            return Err(Error::InvalidInput("initial charge is not a number".to_string()));
+        }
+        Ok(Self { charge: initial_charge.clamp(0.0, capacity), capacity })
+    }
+
+    /// Integrate `current_amps` flowing for `dt`
+    ///
+    /// Current is positive when charging, matching `Battery::current`. The charge is clamped to
+    /// the pack capacity.
+    pub fn update(&mut self, current_amps: f64, dt: Duration) {
+        let delta = current_amps * dt.as_secs_f64() / 3600.0;
+        self.charge = (self.charge + delta).clamp(0.0, self.capacity);
+    }
+
+    /// The integrated remaining charge in Ah
+    pub fn charge(&self) -> f64 {
+        self.charge
+    }
+
+    /// The integrated state of charge in percent
+    pub fn soc(&self) -> f64 {
+        self.charge / self.capacity * 100.0
+    }
+}
+
+/// Coulomb count `battery`'s state of charge in a background task
+///
+/// Starts from `initial_soc` percent of the pack capacity, then reads the current every
+/// `interval` and publishes the integrated SOC in percent on the returned channel. If a read
+/// fails the last SOC is held, and the next good reading is integrated over the whole gap.
+/// Until a positive capacity has been read the channel holds `initial_soc`, so a battery reporting
+/// a capacity of zero is never tracked. The task runs until it is aborted or every receiver is
+/// dropped.
+pub fn spawn_soc_tracker(battery: Battery, initial_soc: f64, interval: Duration) -> (JoinHandle<()>, watch::Receiver<f64>) {
+    let (tx, rx) = watch::channel(initial_soc);
+    let handle = tokio::spawn(async move {
+        let mut ticker = crate::poll_ticker(interval);
+        let mut counter = None;
+        let mut last_sample = battery.clock.now();
+        while !tx.is_closed() {
+            ticker.tick().await;
+            let counter = match &mut counter {
+                Some(counter) => counter,
+                // A capacity which isn't positive is retried on the next tick, like a failed read
+                None => match battery.capacity().await.and_then(|capacity| {
+                    CoulombCounter::new(capacity * initial_soc / 100.0, capacity)
+                }) {
+                    Ok(new) => {
+                        last_sample = battery.clock.now();
+                        counter.insert(new)
+                    }
+                    Err(_) => continue,
+                },
+            };
+            if let Ok(current) = battery.current().await {
+                let now = battery.clock.now();
+                counter.update(current, now.saturating_duration_since(last_sample));
+                last_sample = now;
+                tx.send_replace(counter.soc());
+            }
+        }
+    });
+    (handle, rx)
+}
@@ -0,0 +1,135 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::{Stream, StreamExt};
+
+use crate::{BatteryState, Result};
+
+const HEADER: &str = "timestamp,current,voltage,remaining_charge,capacity,cycle_number,\
+    cell_voltage_1,cell_voltage_2,cell_voltage_3,cell_voltage_4,\
+    cell_temp_1,cell_temp_2,cell_temp_3,cell_temp_4,\
+    heater_level,charge_under_temp";
+
+/// Writes battery states as CSV rows to a sink
+///
+/// The header is written before the first row, and the sink is flushed after every row so a
+/// recording survives the process being killed. Timestamps are seconds since the unix epoch.
+pub struct CsvRecorder<W: Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, header_written: false }
+    }
+
+    /// Write one row for `state`, read at `timestamp`
+    pub fn record(&mut self, timestamp: SystemTime, state: &BatteryState) -> Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "{}", HEADER)?;
+            self.header_written = true;
+        }
+        let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        writeln!(
+            self.writer,
+            "{:.3},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            timestamp,
+            state.current,
+            state.voltage,
+            state.remaining_charge,
+            state.capacity,
+            state.cycle_number,
+            state.cell_voltage_1,
+            state.cell_voltage_2,
+            state.cell_voltage_3,
+            state.cell_voltage_4,
+            state.cell_temp_1,
+            state.cell_temp_2,
+            state.cell_temp_3,
+            state.cell_temp_4,
+            state.heater_level,
+            state.status.charge_under_temp,
+        )?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Record every state from `stream` until it ends, timestamped as they arrive
+    ///
+    /// Failed reads are skipped, so a flaky bus leaves gaps rather than ending the recording.
+    /// Returns early only if writing to the sink fails.
+    pub async fn run<S>(&mut self, stream: S) -> Result<()>
+    where
+        S: Stream<Item = Result<BatteryState>>,
+    {
+        let mut stream = std::pin::pin!(stream);
+        while let Some(reading) = stream.next().await {
+            if let Ok(state) = reading {
+                self.record(SystemTime::now(), &state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the sink back
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn state() -> BatteryState {
+        let mut state = BatteryState {
+            current: -1.5,
+            voltage: 13.2,
+            remaining_charge: 50.0,
+            capacity: 100.0,
+            cycle_number: 12,
+            cell_voltage_1: 3.3,
+            cell_voltage_2: 3.3,
+            cell_voltage_3: 3.2,
+            cell_voltage_4: 3.4,
+            cell_temp_1: 25.0,
+            cell_temp_2: 25.5,
+            cell_temp_3: -1.0,
+            cell_temp_4: 0.0,
+            heater_level: 0.0,
+            ..Default::default()
+        };
+        state.status.charge_under_temp = true;
+        state
+    }
+
+    #[test]
+    fn writes_header_before_first_row() {
+        let mut recorder = CsvRecorder::new(Vec::new());
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        recorder.record(timestamp, &state()).unwrap();
+        let output = String::from_utf8(recorder.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "timestamp,current,voltage,remaining_charge,capacity,cycle_number,\
+             cell_voltage_1,cell_voltage_2,cell_voltage_3,cell_voltage_4,\
+             cell_temp_1,cell_temp_2,cell_temp_3,cell_temp_4,\
+             heater_level,charge_under_temp\n\
+             1700000000.250,-1.5,13.2,50,100,12,3.3,3.3,3.2,3.4,25,25.5,-1,0,0,true\n"
+        );
+    }
+
+    #[test]
+    fn writes_header_once() {
+        let mut recorder = CsvRecorder::new(Vec::new());
+        recorder.record(UNIX_EPOCH, &state()).unwrap();
+        recorder.record(UNIX_EPOCH + Duration::from_secs(1), &BatteryState::default()).unwrap();
+        let output = String::from_utf8(recorder.into_inner()).unwrap();
+        assert_eq!(output.lines().count(), 3);
+        assert_eq!(output.lines().filter(|line| line.starts_with("timestamp,")).count(), 1);
+        assert_eq!(output.lines().last(), Some("1.000,0,0,0,0,0,0,0,0,0,0,0,0,0,0,false"));
+    }
+}
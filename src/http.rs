@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::{json_number, Battery, BatteryState, Result};
+
+/// How long a client gets to send its request before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Poll `battery` every `interval` and serve the latest reading as JSON over HTTP on `addr`
+///
+/// Every request, whatever its path, gets the last successful `read_all` as a JSON object of
+/// `BatteryState::as_map` plus a `timestamp` in seconds since the unix epoch, or a 503 if there
+/// has been no successful read yet. Each connection is answered on its own task, so a client which
+/// is slow to send its request doesn't hold up the others. Only returns if the listener fails.
+pub async fn serve(battery: Battery, addr: SocketAddr, interval: Duration) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let latest: Mutex<Option<(SystemTime, BatteryState)>> = Mutex::new(None);
+
+    let poll = async {
+        let mut ticker = crate::poll_ticker(interval);
+        loop {
+            ticker.tick().await;
+            if let Ok(state) = battery.read_all().await {
+                *latest.lock().unwrap_or_else(|e| e.into_inner()) = Some((SystemTime::now(), state));
+            }
+        }
+    };
+
+    let accept = async {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let body = latest.lock().unwrap_or_else(|e| e.into_inner()).map(|(t, s)| to_json(t, &s));
+            // A misbehaving client only loses its own response
+            tokio::spawn(respond(stream, body));
+        }
+    };
+
+    tokio::select! {
+        () = poll => unreachable!(),
+        result = accept => result,
+    }
+}
+
+/// Wait for the request headers, then write the response and close
+async fn respond(mut stream: TcpStream, body: Option<String>) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+        let n = timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await.map_err(|_| crate::Error::Io(std::io::ErrorKind::TimedOut))??;
+        if n == 0 {
+            break;
+        }
+        request.extend(buf.iter().take(n));
+    }
+
+    let response = match body {
+        Some(body) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        None => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn to_json(timestamp: SystemTime, state: &BatteryState) -> String {
+    let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let mut json = format!("{{\"timestamp\":{:.3}", timestamp);
+    for (name, value) in state.as_map() {
+        json.push_str(&format!(",\"{}\":{}", name, json_number(value)));
+    }
+    json.push('}');
+    json
+}
@@ -1,17 +1,198 @@
-use std::sync::Arc;
+// Nothing received over the wire may cause a panic: check lengths and return errors instead
+#![deny(clippy::indexing_slicing)]
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard};
 use tokio::time::timeout;
 use tokio_modbus::client::{Context, rtu};
 use tokio_modbus::prelude::*;
 use tokio_serial::SerialStream;
 
+pub use tokio_modbus::{Exception, FunctionCode};
+
+mod clock;
+pub use clock::{Clock, MockClock, SystemClock};
+mod coulomb;
+pub use coulomb::{spawn_soc_tracker, CoulombCounter};
+#[cfg(feature = "csv")]
+mod csv;
+#[cfg(feature = "csv")]
+pub use csv::CsvRecorder;
+#[cfg(feature = "stream")]
+mod stream;
+#[cfg(feature = "stream")]
+pub use stream::{merged_poll_stream, BufferPolicy};
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "http")]
+pub use http::serve;
+#[cfg(any(test, feature = "mock"))]
+#[cfg_attr(not(feature = "mock"), allow(dead_code))]
+mod mock;
+#[cfg(feature = "mock")]
+pub use mock::MockBattery;
+#[cfg(feature = "mock")]
+mod replay;
+#[cfg(feature = "mock")]
+pub use replay::replay;
+#[cfg(feature = "log")]
+mod result_ext;
+#[cfg(feature = "log")]
+pub use result_ext::ResultExt;
+
 /// The baudrate of battery RS485 comms
 const RENOGY_BAUDRATE: u32 = 9600;
 
+/// The default pause before each request, see `Port::set_frame_delay`
+const FRAME_DELAY: Duration = Duration::from_millis(10);
+
+/// Ports opened with `Port::open_shared`, by canonical device path
+static SHARED_PORTS: std::sync::Mutex<Vec<(std::path::PathBuf, Weak<Mutex<Port>>)>> = std::sync::Mutex::new(Vec::new());
+
+/// A connection to a modbus bus, shared by all batteries on that bus
+///
+/// `Battery` only ever talks to a `Port` through its modbus `Context`, so it works the same
+/// whichever transport backs the port: a local RS485 serial device (`Port::new`), a Modbus TCP
+/// gateway (`Port::connect_tcp`, with the `tcp` feature), or anything else attached with
+/// `Port::from_context`, e.g. `rtu::attach` over a TCP stream for a transparent gateway.
+///
+/// A port makes one transaction at a time, and batteries sharing it take turns through its
+/// mutex. That is what an RS485 bus requires, with one frame on the wire at a time. A Modbus
+/// TCP gateway can often serve several connections at once, so for gateways which do, a
+/// `PortPool` of connections lets batteries read concurrently.
 pub struct Port {
     ctx: Context,
+    /// A second handle to the serial device, for flushing it, if the port is a local serial port
+    serial: Option<Box<dyn tokio_serial::SerialPort>>,
+    /// The slave address the context was last set to
+    slave: Option<u8>,
+    rate_limit: Option<RateLimiter>,
+    transaction_hook: Option<TransactionHook>,
+    /// Sequence number of the last transaction, see `Port::sequence`
+    sequence: u64,
+    /// How the port was opened, for `Port::recycle`
+    transport: Transport,
+    /// When a read from each slave last succeeded, see `Port::idle_time`
+    last_read: BTreeMap<u8, std::time::Instant>,
+    /// Where `last_read` timestamps come from, see `Port::set_clock`
+    clock: Arc<dyn Clock>,
+    /// The pause before each request, see `Port::set_frame_delay`
+    frame_delay: Duration,
+}
+
+/// The parameters a port was opened with
+enum Transport {
+    Serial(String),
+    #[cfg(feature = "tcp")]
+    Tcp(std::net::SocketAddr),
+    /// Attached with `Port::from_context`, so it can't be reopened
+    Context,
+}
+
+/// A callback receiving every transaction made on a port, see `Port::set_transaction_hook`
+pub type TransactionHook = Box<dyn Fn(&Transaction) + Send>;
+
+/// A record of one modbus request and its outcome, as passed to a transaction hook
+///
+/// tokio-modbus does not expose the raw frames, so this holds the decoded request and response
+/// registers rather than bytes on the wire.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    /// Position of this transaction among all transactions made on the port, starting from 1
+    pub sequence: u64,
+    pub slave: u8,
+    pub function: FunctionCode,
+    /// The first register addressed
+    pub addr: u16,
+    /// The number of registers addressed
+    pub count: u16,
+    /// The values written, empty for reads
+    pub written: Vec<u16>,
+    /// The registers read (empty for writes), or the error
+    pub result: Result<Vec<u16>>,
+    /// Time from sending the request to the response (or timeout)
+    pub latency: Duration,
+}
+
+/// Formats the transaction as one line of a transaction log
+///
+/// The fields are separated by spaces: sequence, slave, function code, first register, register
+/// count, latency in microseconds, then `ok` followed by the registers written or read, or
+/// `err` followed by the error, e.g.
+///
+/// `42 247 0x03 0x13b2 2 10391 ok 0x01f4 0x0084`
+///
+/// `replay` reads logs in this format.
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {:#04x} {:#06x} {} {}",
+            self.sequence,
+            self.slave,
+            self.function.value(),
+            self.addr,
+            self.count,
+            self.latency.as_micros()
+        )?;
+        match &self.result {
+            Ok(read) => {
+                f.write_str(" ok")?;
+                for word in self.written.iter().chain(read) {
+                    write!(f, " {:#06x}", word)?;
+                }
+                Ok(())
+            }
+            Err(e) => write!(f, " err {:?}", e),
+        }
+    }
+}
+
+/// A request made through `Port::transact`
+enum Operation<'a> {
+    ReadHoldingRegisters { addr: u16, count: u16 },
+    ReadInputRegisters { addr: u16, count: u16 },
+    #[cfg_attr(not(feature = "write"), allow(dead_code))]
+    WriteSingleRegister { addr: u16, value: u16 },
+    #[cfg_attr(not(feature = "write"), allow(dead_code))]
+    WriteMultipleRegisters { addr: u16, values: &'a [u16] },
+}
+
+/// Token bucket limiting how often a port is used
+struct RateLimiter {
+    /// Tokens added per second
+    rate: f64,
+    /// Maximum number of tokens, i.e. the largest burst allowed
+    capacity: f64,
+    tokens: f64,
+    last_refill: tokio::time::Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+        Self { rate, capacity, tokens: capacity, last_refill: tokio::time::Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = tokio::time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Wait until a token is available, and take it
+    async fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            tokio::time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate)).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
 }
 
 impl Port {
@@ -21,17 +202,266 @@ impl Port {
         ) {
             Ok(serial) => serial,
             Err(e) => match e.kind {
+                tokio_serial::ErrorKind::Io(std::io::ErrorKind::ResourceBusy | std::io::ErrorKind::PermissionDenied) => {
+                    return Err(Error::PortBusy(busy_message(dev, &e.description)))
+                }
                 tokio_serial::ErrorKind::Io(kind) => return Err(Error::Io(kind)),
+                // serialport reports a tty locked by another process as NoDevice, so tell that
+                // apart from a missing device by checking whether the device node exists
+                tokio_serial::ErrorKind::NoDevice if std::path::Path::new(dev).exists() => {
+                    return Err(Error::PortBusy(busy_message(dev, &e.description)))
+                }
                 tokio_serial::ErrorKind::NoDevice => return Err(Error::NoDevice(e.description)),
                 tokio_serial::ErrorKind::InvalidInput => return Err(Error::InvalidInput(e.description)),
                 tokio_serial::ErrorKind::Unknown => return Err(Error::Unknown(e.description)),
             },
         };
+        let control = tokio_serial::SerialPort::try_clone(&serial).ok();
         let ctx = rtu::attach(serial);
-        Ok(Self { ctx })
+        Ok(Self { serial: control, transport: Transport::Serial(dev.to_string()), ..Self::from_context(ctx) })
+    }
+
+    /// Connect to a Modbus TCP gateway in front of an RS485 bus
+    ///
+    /// The battery address is used as the unit identifier, which the gateway maps to the slave
+    /// address on the bus.
+    #[cfg(feature = "tcp")]
+    pub async fn connect_tcp(addr: std::net::SocketAddr) -> Result<Self> {
+        let ctx = tokio_modbus::client::tcp::connect(addr).await?;
+        Ok(Self { transport: Transport::Tcp(addr), ..Self::from_context(ctx) })
+    }
+
+    /// Close the connection and open it again with the original parameters
+    ///
+    /// For recovering a transport which has wedged, typically an RS485 to TCP gateway which
+    /// stops answering until its TCP connection is dropped. Worth trying after several
+    /// consecutive timeouts to batteries which were answering before; it won't help with a
+    /// battery which is off or miswired. The rate limit and transaction hook are kept. Returns
+    /// `Error::Unsupported` for ports made with `Port::from_context`, which can't be reopened.
+    pub async fn recycle(&mut self) -> Result<()> {
+        // Serial devices are opened exclusively, so close the old one before reopening
+        let closed = || rtu::attach(tokio::io::duplex(1).0);
+        match &self.transport {
+            Transport::Serial(dev) => {
+                let dev = dev.clone();
+                self.ctx = closed();
+                self.serial = None;
+                let port = Port::new(&dev)?;
+                self.ctx = port.ctx;
+                self.serial = port.serial;
+            }
+            #[cfg(feature = "tcp")]
+            Transport::Tcp(addr) => {
+                let addr = *addr;
+                self.ctx = closed();
+                self.ctx = tokio_modbus::client::tcp::connect(addr).await?;
+            }
+            Transport::Context => return Err(Error::Unsupported("recycling a port made with from_context")),
+        }
+        self.slave = None;
+        Ok(())
+    }
+
+    /// Open a serial device, or return the already open port if it is in use elsewhere
+    ///
+    /// All batteries on one physical bus must share a single `Port`: opening the same device
+    /// twice either fails or has the two handles garble each other's transactions. This makes
+    /// it safe to call once per battery with the same device path. Paths are compared after
+    /// resolving symlinks, so e.g. `/dev/ttyUSB0` and its `/dev/serial/by-id` link share a port.
+    pub fn open_shared(dev: &str) -> Result<Arc<Mutex<Self>>> {
+        // A path which can't be resolved is compared as given, and opening it will likely fail
+        let key = std::fs::canonicalize(dev).unwrap_or_else(|_| dev.into());
+        let mut ports = SHARED_PORTS.lock().unwrap_or_else(|e| e.into_inner());
+        ports.retain(|(_, port)| port.strong_count() > 0);
+        if let Some(port) = ports.iter().find(|(path, _)| *path == key).and_then(|(_, port)| port.upgrade()) {
+            return Ok(port);
+        }
+        let port = Arc::new(Mutex::new(Port::new(dev)?));
+        ports.push((key, Arc::downgrade(&port)));
+        Ok(port)
+    }
+
+    /// Create a port from an already attached modbus context
+    ///
+    /// This allows using an alternative transport, or a mock for testing
+    pub fn from_context(ctx: Context) -> Self {
+        Self {
+            ctx,
+            serial: None,
+            slave: None,
+            rate_limit: None,
+            transaction_hook: None,
+            sequence: 0,
+            transport: Transport::Context,
+            last_read: BTreeMap::new(),
+            clock: Arc::new(SystemClock),
+            frame_delay: FRAME_DELAY,
+        }
+    }
+
+    /// Discard any bytes waiting in the serial device's receive buffer
+    ///
+    /// After a partial or corrupt frame, leftover bytes can keep following responses misaligned.
+    /// Reads flush automatically before retrying a failed attempt, so this is only needed for
+    /// manual recovery. Bytes already taken into the modbus codec's own buffer aren't affected.
+    /// Does nothing for ports which aren't local serial ports.
+    pub fn flush_input(&mut self) -> Result<()> {
+        let Some(serial) = &self.serial else {
+            return Ok(());
+        };
+        serial.clear(tokio_serial::ClearBuffer::Input).map_err(|e| match e.kind {
+            tokio_serial::ErrorKind::Io(kind) => Error::Io(kind),
+            _ => Error::Unknown(e.description),
+        })
+    }
+
+    /// Call `hook` with a record of every transaction made on this port
+    ///
+    /// Useful for capturing protocol traces when debugging model specific quirks. The hook is
+    /// called with the port locked, so it should be quick, e.g. push into a ring buffer.
+    pub fn set_transaction_hook(&mut self, hook: TransactionHook) {
+        self.transaction_hook = Some(hook);
+    }
+
+    /// The sequence number of the last transaction made on this port, or 0 if there hasn't been
+    /// one
+    ///
+    /// Numbers increase by one per transaction, retries included, and match
+    /// `Transaction::sequence`, so a failure seen by a caller can be matched to the hook's trace.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// How long since a read from `slave` on this port last succeeded
+    ///
+    /// `Duration::MAX` if no read from it has succeeded yet. Writes and failed reads don't count.
+    /// Kept per slave, as one battery answering says nothing about whether another on the same
+    /// bus has gone to sleep.
+    pub fn idle_time(&self, slave: u8) -> Duration {
+        let now = self.clock.now();
+        self.last_read.get(&slave).map_or(Duration::MAX, |t| now.saturating_duration_since(*t))
+    }
+
+    /// Take the timestamps behind `idle_time` from `clock` instead of the system clock
+    ///
+    /// Mostly for tests of `Battery::with_wakeup`, with a `MockClock`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Stop calling the transaction hook
+    pub fn clear_transaction_hook(&mut self) {
+        self.transaction_hook = None;
+    }
+
+    /// Limit the number of transactions per second on this port, or remove the limit with `None`
+    ///
+    /// Short bursts of up to one second's worth of transactions are allowed, after which
+    /// transactions wait for their turn. This caps the load on the bus however many batteries
+    /// and pollers share the port.
+    pub fn set_rate_limit(&mut self, reads_per_sec: Option<f64>) {
+        self.rate_limit = reads_per_sec.filter(|rate| *rate > 0.0).map(RateLimiter::new);
+    }
+
+    /// Pause for `delay` before each request instead of the default 10 ms
+    ///
+    /// The pause keeps consecutive frames on an RS485 bus apart. Transports which don't need it,
+    /// like `MockBattery`'s, can set it to zero.
+    pub fn set_frame_delay(&mut self, delay: Duration) {
+        self.frame_delay = delay;
+    }
+
+    /// Address the following requests to `slave`, skipping the call if it's already selected
+    ///
+    /// The port is locked for a whole transaction, so the cached address can't go stale between
+    /// here and the request.
+    fn set_slave(&mut self, slave: u8) {
+        if self.slave != Some(slave) {
+            self.ctx.set_slave(Slave(slave));
+            self.slave = Some(slave);
+        }
+    }
+
+    /// Make one request to `slave`, returning the registers read (empty for writes)
+    async fn transact(&mut self, slave: u8, op: Operation<'_>, op_timeout: Duration) -> Result<Vec<u16>> {
+        if let Some(rate_limit) = &mut self.rate_limit {
+            rate_limit.acquire().await;
+        }
+        self.set_slave(slave);
+        self.sequence += 1;
+
+        // Even a zero sleep waits for the next timer tick, around a millisecond
+        if !self.frame_delay.is_zero() {
+            tokio::time::sleep(self.frame_delay).await;
+        }
+        let start = std::time::Instant::now();
+        // Note that tokio-modbus allocates for read responses internally
+        let result = match op {
+            Operation::ReadHoldingRegisters { addr, count } => {
+                timeout(op_timeout, self.ctx.read_holding_registers(addr, count)).await
+            }
+            Operation::ReadInputRegisters { addr, count } => {
+                timeout(op_timeout, self.ctx.read_input_registers(addr, count)).await
+            }
+            Operation::WriteSingleRegister { addr, value } => {
+                timeout(op_timeout, self.ctx.write_single_register(addr, value))
+                    .await
+                    .map(|r| r.map(|()| Vec::new()))
+            }
+            Operation::WriteMultipleRegisters { addr, values } => {
+                timeout(op_timeout, self.ctx.write_multiple_registers(addr, values))
+                    .await
+                    .map(|r| r.map(|()| Vec::new()))
+            }
+        };
+        let result = match result {
+            // tokio-modbus rejects responses of the wrong length or shape as invalid data
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                Err(Error::InvalidResponse { sequence: Some(self.sequence), message: e.to_string() })
+            }
+            Ok(result) => result.map_err(Error::from),
+            Err(_) => Err(Error::Timeout { sequence: self.sequence }),
+        };
+        if result.is_ok() && matches!(op, Operation::ReadHoldingRegisters { .. } | Operation::ReadInputRegisters { .. }) {
+            self.last_read.insert(slave, self.clock.now());
+        }
+
+        if let Some(hook) = &self.transaction_hook {
+            let (function, addr, count, written) = match op {
+                Operation::ReadHoldingRegisters { addr, count } => {
+                    (FunctionCode::ReadHoldingRegisters, addr, count, Vec::new())
+                }
+                Operation::ReadInputRegisters { addr, count } => {
+                    (FunctionCode::ReadInputRegisters, addr, count, Vec::new())
+                }
+                Operation::WriteSingleRegister { addr, value } => (FunctionCode::WriteSingleRegister, addr, 1, vec![value]),
+                Operation::WriteMultipleRegisters { addr, values } => {
+                    (FunctionCode::WriteMultipleRegisters, addr, values.len() as u16, values.to_vec())
+                }
+            };
+            hook(&Transaction {
+                sequence: self.sequence,
+                slave,
+                function,
+                addr,
+                count,
+                written,
+                result: result.clone(),
+                latency: start.elapsed(),
+            });
+        }
+        result
     }
 }
 
+fn busy_message(dev: &str, description: &str) -> String {
+    format!(
+        "{dev} could not be opened ({description}). Is another process (e.g. a second instance of \
+         this program, ModemManager, or a serial console) using it? Check with `fuser {dev}` or \
+         `lsof {dev}`, and make sure you have permission to access it."
+    )
+}
+
 /// Represents all available battery stats
 #[derive(Clone, Copy, Debug, Default)]
 pub struct BatteryState {
@@ -49,201 +479,2970 @@ pub struct BatteryState {
     pub cell_temp_3: f64,
     pub cell_temp_4: f64,
     pub heater_level: f64,
+    pub status: BatteryStatus,
 }
 
+/// A group of `BatteryState` fields which can be read on its own with `Battery::read_fields`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Current,
+    Voltage,
+    RemainingCharge,
+    Capacity,
+    CycleNumber,
+    /// All four cell voltages, which are read together
+    CellVoltages,
+    /// All four cell temperatures, which are read together
+    CellTemps,
+    HeaterLevel,
+    Status,
+}
 
-pub struct Battery {
-    port: Arc<Mutex<Port>>,
-    addr: u8,
+/// A subset of `BatteryState`, with only the fields requested from `Battery::read_fields` set
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PartialBatteryState {
+    pub current: Option<f64>,
+    pub voltage: Option<f64>,
+    pub remaining_charge: Option<f64>,
+    pub capacity: Option<f64>,
+    pub cycle_number: Option<u16>,
+    pub cell_voltages: Option<[f64; 4]>,
+    pub cell_temps: Option<[f64; 4]>,
+    pub heater_level: Option<f64>,
+    pub status: Option<BatteryStatus>,
 }
 
-#[derive(Debug)]
-pub enum Error {
-    Timeout,
-    NoDevice(String),
-    InvalidInput(String),
-    Unknown(String),
-    Io(std::io::ErrorKind),
+/// The flags reported in the BMS status registers
+///
+/// Over/under flags are protections, which switch off charging or discharging. High/low
+/// flags are warnings, raised before the corresponding protection trips.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatteryStatus {
+    // Status 1 (0x13f2)
+    /// Bit 0: pack voltage below the under-voltage protection threshold
+    pub pack_under_voltage: bool,
+    /// Bit 1: too hot to charge
+    pub charge_over_temp: bool,
+    /// Bit 2: too cold to charge
+    pub charge_under_temp: bool,
+    /// Bit 3: too hot to discharge
+    pub discharge_over_temp: bool,
+    /// Bit 4: too cold to discharge
+    pub discharge_under_temp: bool,
+    /// Bit 5: discharge over-current, level 1
+    pub discharge_over_current_1: bool,
+    /// Bit 6: charge over-current, level 1
+    pub charge_over_current_1: bool,
+    /// Bit 7: a cell is above the over-voltage protection threshold
+    pub cell_over_voltage: bool,
+    /// Bit 8: a cell is below the under-voltage protection threshold
+    pub cell_under_voltage: bool,
+    /// Bit 9: pack voltage above the over-voltage protection threshold
+    pub pack_over_voltage: bool,
+    /// Bit 10: discharge over-current, level 2
+    pub discharge_over_current_2: bool,
+    /// Bit 11: charge over-current, level 2
+    pub charge_over_current_2: bool,
+    /// Bit 12: the BMS is powered from the battery module
+    pub using_battery_power: bool,
+    /// Bit 13: the discharge MOSFET is on
+    pub discharge_mosfet: bool,
+    /// Bit 14: the charge MOSFET is on
+    pub charge_mosfet: bool,
+    /// Bit 15: short circuit protection
+    pub short_circuit: bool,
 
+    // Status 2 (0x13f3)
+    /// Bit 0: a cell voltage is low
+    pub cell_low_voltage: bool,
+    /// Bit 1: a cell voltage is high
+    pub cell_high_voltage: bool,
+    /// Bit 2: pack voltage is low
+    pub pack_low_voltage: bool,
+    /// Bit 3: pack voltage is high
+    pub pack_high_voltage: bool,
+    /// Bit 4: cool for charging
+    pub charge_low_temp: bool,
+    /// Bit 5: warm for charging
+    pub charge_high_temp: bool,
+    /// Bit 6: cool for discharging
+    pub discharge_low_temp: bool,
+    /// Bit 7: warm for discharging
+    pub discharge_high_temp: bool,
+    /// Bit 8: the buzzer is on
+    pub buzzer: bool,
+    /// Bit 11: the battery is fully charged
+    pub fully_charged: bool,
+    /// Bit 13: the heater is on
+    pub heater_on: bool,
+    /// Bit 14: a discharge current is flowing
+    pub discharging: bool,
+    /// Bit 15: a charge current is flowing
+    pub charging: bool,
+
+    // Status 3 (0x13f4)
+    /// Bit n is set if cell n + 1 has a voltage sensing error
+    pub cell_voltage_errors: u16,
+
+    // Charge/discharge status (0x13f5)
+    /// Bit 3: the BMS requests a full charge
+    pub full_charge_request: bool,
+    /// Bit 4: the BMS requests an immediate charge, level 2
+    pub charge_immediately_2: bool,
+    /// Bit 5: the BMS requests an immediate charge, level 1
+    pub charge_immediately_1: bool,
+    /// Bit 6: discharging is allowed
+    pub discharge_enabled: bool,
+    /// Bit 7: charging is allowed
+    pub charge_enabled: bool,
 }
 
-impl From<std::io::Error> for Error {
-    fn from(value: std::io::Error) -> Self {
-        Error::Io(value.kind())
+impl BatteryStatus {
+    fn from_registers(raw: [u16; 4]) -> Self {
+        let [status1, status2, status3, charge_discharge] = raw;
+        let bit = |word: u16, n: u16| word & (1 << n) != 0;
+        Self {
+            pack_under_voltage: bit(status1, 0),
+            charge_over_temp: bit(status1, 1),
+            charge_under_temp: bit(status1, 2),
+            discharge_over_temp: bit(status1, 3),
+            discharge_under_temp: bit(status1, 4),
+            discharge_over_current_1: bit(status1, 5),
+            charge_over_current_1: bit(status1, 6),
+            cell_over_voltage: bit(status1, 7),
+            cell_under_voltage: bit(status1, 8),
+            pack_over_voltage: bit(status1, 9),
+            discharge_over_current_2: bit(status1, 10),
+            charge_over_current_2: bit(status1, 11),
+            using_battery_power: bit(status1, 12),
+            discharge_mosfet: bit(status1, 13),
+            charge_mosfet: bit(status1, 14),
+            short_circuit: bit(status1, 15),
+            cell_low_voltage: bit(status2, 0),
+            cell_high_voltage: bit(status2, 1),
+            pack_low_voltage: bit(status2, 2),
+            pack_high_voltage: bit(status2, 3),
+            charge_low_temp: bit(status2, 4),
+            charge_high_temp: bit(status2, 5),
+            discharge_low_temp: bit(status2, 6),
+            discharge_high_temp: bit(status2, 7),
+            buzzer: bit(status2, 8),
+            fully_charged: bit(status2, 11),
+            heater_on: bit(status2, 13),
+            discharging: bit(status2, 14),
+            charging: bit(status2, 15),
+            cell_voltage_errors: status3,
+            full_charge_request: bit(charge_discharge, 3),
+            charge_immediately_2: bit(charge_discharge, 4),
+            charge_immediately_1: bit(charge_discharge, 5),
+            discharge_enabled: bit(charge_discharge, 6),
+            charge_enabled: bit(charge_discharge, 7),
+        }
     }
-}
 
-pub type Result<T> = std::result::Result<T, Error>;
+    /// True if any protection has tripped, i.e. the BMS has cut charging or discharging
+    pub fn protection_active(&self) -> bool {
+        self.pack_under_voltage
+            || self.charge_over_temp
+            || self.charge_under_temp
+            || self.discharge_over_temp
+            || self.discharge_under_temp
+            || self.discharge_over_current_1
+            || self.charge_over_current_1
+            || self.cell_over_voltage
+            || self.cell_under_voltage
+            || self.pack_over_voltage
+            || self.discharge_over_current_2
+            || self.charge_over_current_2
+            || self.short_circuit
+    }
 
+    /// Every status flag as a named `Condition`, see `Condition` for the table
+    pub fn conditions(&self) -> Vec<Condition> {
+        use Severity::*;
+        let table = [
+            ("short_circuit", Protection, self.short_circuit),
+            ("cell_over_voltage", Protection, self.cell_over_voltage),
+            ("cell_under_voltage", Protection, self.cell_under_voltage),
+            ("pack_over_voltage", Protection, self.pack_over_voltage),
+            ("pack_under_voltage", Protection, self.pack_under_voltage),
+            ("charge_over_current_1", Protection, self.charge_over_current_1),
+            ("charge_over_current_2", Protection, self.charge_over_current_2),
+            ("discharge_over_current_1", Protection, self.discharge_over_current_1),
+            ("discharge_over_current_2", Protection, self.discharge_over_current_2),
+            ("charge_over_temp", Protection, self.charge_over_temp),
+            ("charge_under_temp", Protection, self.charge_under_temp),
+            ("discharge_over_temp", Protection, self.discharge_over_temp),
+            ("discharge_under_temp", Protection, self.discharge_under_temp),
+            ("cell_voltage_error", Protection, self.cell_voltage_errors != 0),
+            ("cell_high_voltage", Warning, self.cell_high_voltage),
+            ("cell_low_voltage", Warning, self.cell_low_voltage),
+            ("pack_high_voltage", Warning, self.pack_high_voltage),
+            ("pack_low_voltage", Warning, self.pack_low_voltage),
+            ("charge_high_temp", Warning, self.charge_high_temp),
+            ("charge_low_temp", Warning, self.charge_low_temp),
+            ("discharge_high_temp", Warning, self.discharge_high_temp),
+            ("discharge_low_temp", Warning, self.discharge_low_temp),
+            ("charge_immediately_1", Warning, self.charge_immediately_1),
+            ("charge_immediately_2", Warning, self.charge_immediately_2),
+            ("full_charge_request", Info, self.full_charge_request),
+            ("fully_charged", Info, self.fully_charged),
+            ("charging", Info, self.charging),
+            ("discharging", Info, self.discharging),
+            ("using_battery_power", Info, self.using_battery_power),
+            ("charge_mosfet", Info, self.charge_mosfet),
+            ("discharge_mosfet", Info, self.discharge_mosfet),
+            ("charge_enabled", Info, self.charge_enabled),
+            ("discharge_enabled", Info, self.discharge_enabled),
+            ("heater_on", Info, self.heater_on),
+            ("buzzer", Info, self.buzzer),
+        ];
+        table.into_iter().map(|(name, severity, active)| Condition { name, severity, active }).collect()
+    }
 
-#[repr(u16)]
-enum RegAddr {
-    Current = 0x13b2,
-    Voltage = 0x13b3,
-    RemainingCharge = 0x13b4,
-    Capacity = 0x13b6,
-    CycleNumber = 0x13b8,
-    CellVoltage1 = 0x1389,
-    CellVoltage2 = 0x138a,
-    CellVoltage3 = 0x138b,
-    CellVoltage4 = 0x138c,
-    CellTemp1 = 0x139a,
-    CellTemp2 = 0x139b,
-    CellTemp3 = 0x139c,
-    CellTemp4 = 0x139d,
-    HeaterLevel = 0x13ef,
+    /// True if any warning is raised, i.e. a value is nearing a protection threshold
+    pub fn warning_active(&self) -> bool {
+        self.cell_low_voltage
+            || self.cell_high_voltage
+            || self.pack_low_voltage
+            || self.pack_high_voltage
+            || self.charge_low_temp
+            || self.charge_high_temp
+            || self.discharge_low_temp
+            || self.discharge_high_temp
+    }
 }
 
-impl Battery {
-    pub fn new(port: Arc<Mutex<Port>>, addr: u8) -> Self {
-        Self { port, addr }
-    }
+/// How serious a `Condition` is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Normal operating state
+    Info,
+    /// A value is nearing a protection threshold, or the BMS wants charging
+    Warning,
+    /// The BMS has cut charging or discharging
+    Protection,
+}
 
-    pub async fn read_register(&self, addr: u16, size: u16) -> Result<Vec<u16>> {
-        const TIMEOUT: Duration = Duration::from_millis(200);
-        let mut port = self.port.lock().await;
-        port.ctx.set_slave(Slave(self.addr));
+/// One named status flag, see `BatteryStatus::conditions`
+///
+/// Every condition is always listed, with `active` saying whether it is raised:
+///
+/// | Severity | Conditions |
+/// |----------|------------|
+/// | Protection | short_circuit, cell_over_voltage, cell_under_voltage, pack_over_voltage, pack_under_voltage, charge_over_current_1, charge_over_current_2, discharge_over_current_1, discharge_over_current_2, charge_over_temp, charge_under_temp, discharge_over_temp, discharge_under_temp, cell_voltage_error |
+/// | Warning | cell_high_voltage, cell_low_voltage, pack_high_voltage, pack_low_voltage, charge_high_temp, charge_low_temp, discharge_high_temp, discharge_low_temp, charge_immediately_1, charge_immediately_2 |
+/// | Info | full_charge_request, fully_charged, charging, discharging, using_battery_power, charge_mosfet, discharge_mosfet, charge_enabled, discharge_enabled, heater_on, buzzer |
+///
+/// Names match the `BatteryStatus` fields, except cell_voltage_error which is set if any cell
+/// has a voltage sensing error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub active: bool,
+}
+
+/// LiFePO4 cells must not be charged below this temperature in deg C
+pub const MIN_CHARGE_TEMP: f64 = 0.0;
+
+/// Number of cell voltage registers in the Renogy smart lithium map, from 0x1389
+///
+/// The temperature sensor count follows them, so no read of cell voltages may go further.
+pub const MAX_CELLS: u16 = 16;
+
+/// A reasonable deadband in Amps for `BatteryState::flow_state`
+pub const DEFAULT_FLOW_DEADBAND: f64 = 0.1;
 
-        std::thread::sleep(Duration::from_millis(10));
-        match timeout(TIMEOUT, port.ctx.read_holding_registers(addr, size)).await {
-            Ok(result) => result.map_err(|e| Error::Io(e.kind())),
-            Err(_) => Err(Error::Timeout),
+/// Percent per count of the heater level register, which holds a 0-255 PWM duty cycle
+///
+/// The old scale factor 0.3922 read 255 as 100.011 %.
+pub const HEATER_LEVEL_SCALE: f64 = 100.0 / 255.0;
+
+/// The byte order a BMS sends its registers in, see `Battery::detect_byte_order`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// High byte first, as modbus specifies
+    #[default]
+    BigEndian,
+    /// Low byte first
+    Swapped,
+}
+
+/// Whether the battery is charging, discharging, or neither
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowState {
+    Charging,
+    Discharging,
+    Idle,
+}
+
+impl BatteryState {
+    /// Classify the current flow, treating currents smaller than `deadband_amps` as idle
+    ///
+    /// The deadband keeps the result from flickering as the current hovers around zero
+    pub fn flow_state(&self, deadband_amps: f64) -> FlowState {
+        if self.current > deadband_amps {
+            FlowState::Charging
+        } else if self.current < -deadband_amps {
+            FlowState::Discharging
+        } else {
+            FlowState::Idle
         }
     }
 
-    /// Read a raw u16 value from a register
-    pub async fn read_u16(&self, addr: u16) -> Result<u16> {
-        let raw_value = self.read_register(addr, 1).await?;
-        assert!(raw_value.len() == 1);
-        Ok(raw_value[0])
+    /// All numeric fields by name, for generic metric pipelines
+    pub fn as_map(&self) -> BTreeMap<&'static str, f64> {
+        BTreeMap::from([
+            ("current", self.current),
+            ("voltage", self.voltage),
+            ("remaining_charge", self.remaining_charge),
+            ("capacity", self.capacity),
+            ("cycle_number", self.cycle_number as f64),
+            ("cell_voltage_1", self.cell_voltage_1),
+            ("cell_voltage_2", self.cell_voltage_2),
+            ("cell_voltage_3", self.cell_voltage_3),
+            ("cell_voltage_4", self.cell_voltage_4),
+            ("cell_temp_1", self.cell_temp_1),
+            ("cell_temp_2", self.cell_temp_2),
+            ("cell_temp_3", self.cell_temp_3),
+            ("cell_temp_4", self.cell_temp_4),
+            ("heater_level", self.heater_level),
+        ])
     }
 
-    /// Read a raw i16 value from a register
-    pub async fn read_i16(&self, addr: u16) -> Result<i16> {
-        let raw_value = self.read_register(addr, 1).await?;
-        assert!(raw_value.len() == 1);
-        Ok(raw_value[0] as i16)
+    /// The cell voltages in V, as an array
+    pub fn cell_voltages(&self) -> [f64; 4] {
+        [self.cell_voltage_1, self.cell_voltage_2, self.cell_voltage_3, self.cell_voltage_4]
     }
 
-    /// Read a raw u32 value from a register
-    pub async fn read_u32(&self, addr: u16) -> Result<u32> {
-        let raw_value = self.read_register(addr, 2).await?;
-        assert!(raw_value.len() == 2);
-        Ok(raw_value[1] as u32 + ((raw_value[0] as u32) << 16))
+    /// The cell temperatures in deg C, as an array
+    pub fn cell_temps(&self) -> [f64; 4] {
+        [self.cell_temp_1, self.cell_temp_2, self.cell_temp_3, self.cell_temp_4]
     }
 
-    /// Get the battery current in Amps
+    /// The 0 based index and temperature in deg C of the hottest cell
     ///
-    /// Current is negative when discharging, positive when charging
-    pub async fn current(&self) -> Result<f64> {
-        let raw = self.read_i16(RegAddr::Current as u16).await?;
-        Ok(raw as f64 * 0.01)
+    /// A cell which consistently runs hotter than the rest often has a bad weld or is failing.
+    /// Ties go to the lowest index.
+    pub fn hottest_cell(&self) -> (usize, f64) {
+        hottest_cell(&self.cell_temps()).unwrap_or_default()
     }
 
-    /// Get the battery voltage in Volts
-    pub async fn voltage(&self) -> Result<f64> {
-        let raw = self.read_u16(RegAddr::Voltage as u16).await?;
-        Ok(raw as f64 * 0.1)
+    /// The measured pack voltage minus the sum of the cell voltages, in V
+    ///
+    /// The pack and cell voltages are each reported with 0.1 V resolution, so rounding alone
+    /// accounts for up to 0.25 V. A discrepancy persistently above ~0.5 V points to a sensing
+    /// fault or a bad connection between cells. Only meaningful for 4 cell (12V) packs.
+    pub fn pack_vs_cells_discrepancy(&self) -> f64 {
+        self.voltage - self.cell_voltages().iter().sum::<f64>()
     }
 
-    /// Get state of charge
+    /// True if the battery will refuse to charge because it is too cold
     ///
-    /// Returns the estimated remaining charge in Ah
-    pub async fn remaining_charge(&self) -> Result<f64> {
-        let raw = self.read_u32(RegAddr::RemainingCharge as u16).await?;
-        Ok(raw as f64 * 0.001)
+    /// This is set when either the BMS reports its charge under-temperature protection, or any
+    /// cell is below `MIN_CHARGE_TEMP`.
+    pub fn charge_locked_out(&self) -> bool {
+        let coldest = self.cell_temps().into_iter().fold(f64::INFINITY, f64::min);
+        self.status.charge_under_temp || coldest < MIN_CHARGE_TEMP
     }
 
-    /// Get the total battery capacity
+    /// State of charge in percent, computed as remaining_charge / capacity
     ///
-    /// Returns the battery capacity (when fully charged) in Ah
-    pub async fn capacity(&self) -> Result<f64> {
-        let raw = self.read_u32(RegAddr::Capacity as u16).await?;
-        Ok(raw as f64 * 0.001)
+    /// The result is clamped to 0..=100, so a BMS reporting more charge than capacity reads as
+    /// full; see `charge_consistent` to detect that.
+    pub fn state_of_charge(&self) -> f64 {
+        (self.remaining_charge / self.capacity * 100.0).clamp(0.0, 100.0)
     }
 
-    /// Get the battery cycle counter value
-    pub async fn cycle_number(&self) -> Result<u16> {
-        Ok(self.read_u16(RegAddr::CycleNumber as u16).await?)
+    /// False if the remaining charge exceeds the capacity by more than 5 %
+    ///
+    /// A small excess is normal just after a full charge, but a large one means a corrupted or
+    /// misconfigured BMS.
+    pub fn charge_consistent(&self) -> bool {
+        self.remaining_charge <= self.capacity * 1.05
     }
 
-    /// Get individual cell voltage 1 in V
-    pub async fn cell_voltage_1(&self) -> Result<f64> {
-        let raw = self.read_u16(RegAddr::CellVoltage1 as u16).await?;
-        Ok(raw as f64 * 0.1)
+    /// Render the state in the OpenMetrics text exposition format
+    ///
+    /// Every metric is a gauge prefixed with `renogy_` and carries `labels`, e.g.
+    /// `&[("serial", "R12345")]`. Per-cell metrics add a 1 based `cell` label. The output ends
+    /// with the `# EOF` terminator, so it can be served as is with content type
+    /// `application/openmetrics-text; version=1.0.0`.
+    pub fn to_openmetrics(&self, labels: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        let mut gauge = |name: &str, samples: &[(Option<usize>, f64)]| {
+            out.push_str(&format!("# TYPE renogy_{} gauge\n", name));
+            for (cell, value) in samples {
+                let mut set: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v))).collect();
+                if let Some(cell) = cell {
+                    set.push(format!("cell=\"{}\"", cell + 1));
+                }
+                let set = if set.is_empty() { String::new() } else { format!("{{{}}}", set.join(",")) };
+                out.push_str(&format!("renogy_{}{} {}\n", name, set, openmetrics_number(*value)));
+            }
+        };
+        gauge("current_amperes", &[(None, self.current)]);
+        gauge("voltage_volts", &[(None, self.voltage)]);
+        gauge("remaining_charge_amp_hours", &[(None, self.remaining_charge)]);
+        gauge("capacity_amp_hours", &[(None, self.capacity)]);
+        gauge("cycle_count", &[(None, self.cycle_number as f64)]);
+        let cells = |values: [f64; 4]| values.into_iter().enumerate().map(|(i, v)| (Some(i), v)).collect::<Vec<_>>();
+        gauge("cell_voltage_volts", &cells(self.cell_voltages()));
+        gauge("cell_temperature_celsius", &cells(self.cell_temps()));
+        gauge("heater_level_percent", &[(None, self.heater_level)]);
+        gauge("state_of_charge_percent", &[(None, self.state_of_charge())]);
+        out.push_str("# EOF\n");
+        out
     }
+}
 
-    /// Get individual cell voltage 2 in V
-    pub async fn cell_voltage_2(&self) -> Result<f64> {
-        let raw = self.read_u16(RegAddr::CellVoltage2 as u16).await?;
-        Ok(raw as f64 * 0.1)
-    }
+/// Escape a label value for the OpenMetrics text format
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
 
-    /// Get individual cell voltage 3 in V
-    pub async fn cell_voltage_3(&self) -> Result<f64> {
-        let raw = self.read_u16(RegAddr::CellVoltage3 as u16).await?;
-        Ok(raw as f64 * 0.1)
+/// Format a number for OpenMetrics, which spells out non-finite values
+fn openmetrics_number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
     }
+}
 
-    /// Get individual cell voltage 4 in V
-    pub async fn cell_voltage_4(&self) -> Result<f64> {
-        let raw = self.read_u16(RegAddr::CellVoltage4 as u16).await?;
-        Ok(raw as f64 * 0.1)
+/// A `BatteryState` flattened into typed columns for time-series databases
+///
+/// Each field name ends with its unit, and per-cell values are expanded into numbered columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TelemetryRecord {
+    /// Seconds since the unix epoch
+    pub timestamp_s: f64,
+    pub current_a: f64,
+    pub voltage_v: f64,
+    pub remaining_charge_ah: f64,
+    pub capacity_ah: f64,
+    pub cycle_number: u16,
+    pub cell_1_voltage_v: f64,
+    pub cell_2_voltage_v: f64,
+    pub cell_3_voltage_v: f64,
+    pub cell_4_voltage_v: f64,
+    pub cell_1_temp_c: f64,
+    pub cell_2_temp_c: f64,
+    pub cell_3_temp_c: f64,
+    pub cell_4_temp_c: f64,
+    pub heater_level_pct: f64,
+    /// Computed from remaining charge and capacity, see `BatteryState::state_of_charge`
+    pub state_of_charge_pct: f64,
+}
+
+impl From<(&BatteryState, std::time::SystemTime)> for TelemetryRecord {
+    fn from((state, timestamp): (&BatteryState, std::time::SystemTime)) -> Self {
+        TelemetryRecord {
+            timestamp_s: timestamp.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            current_a: state.current,
+            voltage_v: state.voltage,
+            remaining_charge_ah: state.remaining_charge,
+            capacity_ah: state.capacity,
+            cycle_number: state.cycle_number,
+            cell_1_voltage_v: state.cell_voltage_1,
+            cell_2_voltage_v: state.cell_voltage_2,
+            cell_3_voltage_v: state.cell_voltage_3,
+            cell_4_voltage_v: state.cell_voltage_4,
+            cell_1_temp_c: state.cell_temp_1,
+            cell_2_temp_c: state.cell_temp_2,
+            cell_3_temp_c: state.cell_temp_3,
+            cell_4_temp_c: state.cell_temp_4,
+            heater_level_pct: state.heater_level,
+            state_of_charge_pct: state.state_of_charge(),
+        }
     }
+}
 
-    /// Read individual cell temperature 1 in deg C
-    pub async fn cell_temp_1(&self) -> Result<f64> {
-        let raw = self.read_i16(RegAddr::CellTemp1 as u16).await?;
-        Ok(raw as f64 * 0.1)
+/// The readings behind a typical Home Assistant battery sensor set, see
+/// `Battery::read_ha_bundle`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HaBundle {
+    /// In V
+    pub voltage: f64,
+    /// In Amps, negative when discharging
+    pub current: f64,
+    /// In W, negative when discharging
+    pub power: f64,
+    /// State of charge in percent, computed like `BatteryState::state_of_charge`
+    pub battery: f64,
+    /// Mean cell temperature in deg C
+    pub temperature: f64,
+    /// "protection", "warning", "charging", "discharging" or "idle", in that order of precedence
+    pub status: &'static str,
+}
+
+impl HaBundle {
+    /// A JSON object keyed by Home Assistant's sensor device class names
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"voltage\":{},\"current\":{},\"power\":{},\"battery\":{},\"temperature\":{},\"status\":\"{}\"}}",
+            json_number(self.voltage),
+            json_number(self.current),
+            json_number(self.power),
+            json_number(self.battery),
+            json_number(self.temperature),
+            self.status
+        )
     }
+}
 
-    /// Read individual cell temperature 2 in deg C
-    pub async fn cell_temp_2(&self) -> Result<f64> {
-        let raw = self.read_i16(RegAddr::CellTemp2 as u16).await?;
-        Ok(raw as f64 * 0.1)
+/// Format a number for JSON, which has no NaN or infinity
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
     }
+}
 
-    /// Read individual cell temperature 3 in deg C
-    pub async fn cell_temp_3(&self) -> Result<f64> {
-        let raw = self.read_i16(RegAddr::CellTemp3 as u16).await?;
-        Ok(raw as f64 * 0.1)
+/// Aggregate readings for a bank of batteries wired in parallel
+///
+/// Build one from the states of each pack with `BankStats::from(&states[..])`. An empty slice
+/// gives a zero voltage and a minimum state of charge of 0.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BankStats {
+    /// Number of packs in the bank
+    pub pack_count: usize,
+    /// Sum of the pack currents in Amps
+    pub total_current: f64,
+    /// Mean of the pack voltages in V
+    pub average_voltage: f64,
+    /// Sum of the remaining charge in Ah
+    pub remaining_charge: f64,
+    /// Sum of the capacities in Ah
+    pub capacity: f64,
+    /// Lowest state of charge of any pack in percent
+    pub min_state_of_charge: f64,
+}
+
+impl From<&[BatteryState]> for BankStats {
+    fn from(states: &[BatteryState]) -> Self {
+        if states.is_empty() {
+            return BankStats::default();
+        }
+        let pack_count = states.len();
+        BankStats {
+            pack_count,
+            total_current: states.iter().map(|s| s.current).sum(),
+            average_voltage: states.iter().map(|s| s.voltage).sum::<f64>() / pack_count as f64,
+            remaining_charge: states.iter().map(|s| s.remaining_charge).sum(),
+            capacity: states.iter().map(|s| s.capacity).sum(),
+            min_state_of_charge: states
+                .iter()
+                .map(|s| s.state_of_charge())
+                .fold(f64::INFINITY, f64::min),
+        }
     }
+}
 
-    /// Read individual cell temperature 4 in deg C
-    pub async fn cell_temp_4(&self) -> Result<f64> {
-        let raw = self.read_i16(RegAddr::CellTemp4 as u16).await?;
-        Ok(raw as f64 * 0.1)
+/// Known Renogy battery models
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RenogyModel {
+    /// 12V 100Ah smart lithium iron phosphate battery
+    Rbt100Lfp12S,
+    /// 48V 200Ah smart lithium iron phosphate battery
+    Rbt200Lfp48S,
+    /// Any other model, with the model string the battery reported
+    Unknown(String),
+}
+
+impl RenogyModel {
+    /// Identify a model from the model string reported by the battery
+    ///
+    /// Model strings may carry a suffix (e.g. a hardware generation), so only the prefix is
+    /// matched
+    pub fn from_model_string(model: &str) -> Self {
+        if model.starts_with("RBT100LFP12S") {
+            RenogyModel::Rbt100Lfp12S
+        } else if model.starts_with("RBT200LFP48S") {
+            RenogyModel::Rbt200Lfp48S
+        } else {
+            RenogyModel::Unknown(model.to_string())
+        }
     }
 
-    /// Read heater level in percent
-    pub async fn heater_level(&self) -> Result<f64> {
-        let raw = self.read_u16(RegAddr::HeaterLevel as u16).await?;
-        Ok(raw as f64 * 0.3922)
+    /// The register map to use for this model
+    ///
+    /// The known models all use the default map. Unknown models get the default map too, which
+    /// is the best guess for other Renogy smart lithium batteries.
+    pub fn register_map(&self) -> RegisterMap {
+        RegisterMap::default()
     }
+}
 
+/// A remaining time, either reported by the BMS or estimated by this crate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeEstimate {
+    pub duration: Duration,
+    /// True if computed from the present charge and current rather than read from the BMS
+    pub estimated: bool,
+}
 
-    pub async fn test(&self) {
-        let mut port = self.port.lock().await;
-        port.ctx.set_slave(Slave(240));
+/// The highest and lowest cell voltages in a pack
+///
+/// Indices are 0 based, so `max_idx == 0` means cell 1
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CellExtremes {
+    pub max_v: f64,
+    pub max_idx: usize,
+    pub min_v: f64,
+    pub min_idx: usize,
+}
 
+impl CellExtremes {
+    /// Find the extremes of `cells`, which must not be empty
+    fn from_cells(cells: &[f64]) -> Result<Self> {
+        if cells.is_empty() {
+            return Err(Error::InvalidResponse { sequence: None, message: "cell count is 0".to_string() });
+        }
+        let mut extremes = CellExtremes { max_v: f64::NEG_INFINITY, min_v: f64::INFINITY, ..Default::default() };
+        for (i, v) in cells.iter().copied().enumerate() {
+            if v > extremes.max_v {
+                extremes.max_v = v;
+                extremes.max_idx = i;
+            }
+            if v < extremes.min_v {
+                extremes.min_v = v;
+                extremes.min_idx = i;
+            }
+        }
+        Ok(extremes)
     }
+}
 
-    pub async fn read_all(&self) -> Result<BatteryState> {
-        Ok(BatteryState {
-            current: self.current().await?,
-            voltage: self.voltage().await?,
-            remaining_charge: self.remaining_charge().await?,
-            capacity: self.capacity().await?,
-            cell_voltage_1: self.cell_voltage_1().await?,
-            cell_voltage_2: self.cell_voltage_2().await?,
-            cell_voltage_3: self.cell_voltage_3().await?,
-            cell_voltage_4: self.cell_voltage_4().await?,
-            cycle_number: self.cycle_number().await?,
-            cell_temp_1: self.cell_temp_1().await?,
-            cell_temp_2: self.cell_temp_2().await?,
-            cell_temp_3: self.cell_temp_3().await?,
-            cell_temp_4: self.cell_temp_4().await?,
-            heater_level: self.heater_level().await?,
+/// The 0 based index and temperature of the hottest of any number of cell temperatures
+///
+/// Returns `None` for an empty slice. Ties go to the lowest index.
+pub fn hottest_cell(temps: &[f64]) -> Option<(usize, f64)> {
+    temps
+        .iter()
+        .copied()
+        .enumerate()
+        .fold(None, |hottest, (i, t)| match hottest {
+            Some((_, max)) if max >= t => hottest,
+            _ => Some((i, t)),
         })
+}
+
+/// The charge and discharge current limits the BMS is currently enforcing
+///
+/// All values are in Amps
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CurrentLimits {
+    pub charge_limit: f64,
+    pub discharge_limit: f64,
+}
+
+/// The result of `Battery::verify_cell_count`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CellCountCheck {
+    /// The cell count the BMS reports
+    pub reported: u16,
+    /// How many of the 16 cell voltage registers hold a plausible cell voltage
+    pub responding: u16,
+    /// 0 based indices of cells within the reported count without a plausible voltage
+    pub implausible: Vec<usize>,
+}
+
+impl CellCountCheck {
+    /// True if exactly the reported cells have plausible voltages
+    pub fn is_ok(&self) -> bool {
+        self.reported == self.responding && self.implausible.is_empty()
+    }
+}
+
+/// The number of cell voltage and temperature sensors a battery has
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SensorLayout {
+    pub cell_count: u16,
+    pub temp_count: u16,
+}
+
+/// Protection thresholds configured in the BMS
+///
+/// All values are in Volts
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtectionConfig {
+    pub cell_over_voltage: f64,
+    pub cell_under_voltage: f64,
+    pub pack_over_voltage: f64,
+    pub pack_under_voltage: f64,
+}
+
+/// The temperature range the BMS allows charging and discharging in
+///
+/// These are the protection limits: outside them the BMS cuts charging or discharging. All
+/// values are in deg C.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TempLimits {
+    pub charge_min: f64,
+    pub charge_max: f64,
+    pub discharge_min: f64,
+    pub discharge_max: f64,
+}
+
+/// Static information identifying a battery, which doesn't change while it is running
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatteryInfo {
+    pub model: String,
+    pub serial_number: String,
+    /// Empty if the BMS doesn't populate the manufacturer name, see `Battery::vendor_id`
+    pub vendor_id: String,
+}
+
+/// How temperature registers are encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TemperatureEncoding {
+    /// Signed i16 in units of 0.1 deg C
+    ///
+    /// This is what the RBT series smart lithium batteries this crate was developed against use
+    #[default]
+    SignedTenths,
+    /// Unsigned u16 in units of 0.1 deg C, offset by +40 deg C so that sub-zero temperatures
+    /// stay positive
+    ///
+    /// If temperatures read roughly 40 deg C too high with `SignedTenths`, the battery likely uses
+    /// this encoding.
+    OffsetBy40,
+}
+
+impl TemperatureEncoding {
+    fn decode(&self, raw: u16) -> f64 {
+        match self {
+            TemperatureEncoding::SignedTenths => raw as i16 as f64 * 0.1,
+            TemperatureEncoding::OffsetBy40 => raw as f64 * 0.1 - 40.0,
+        }
+    }
+}
+
+/// Units of the core measurement registers, as the value of one count in canonical units
+///
+/// Accessors multiply the raw register value by these, so they return Amps, Volts and Ah
+/// whatever the model's native resolution. The defaults are the Renogy smart lithium units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scales {
+    /// Amps per count of the current registers, default 0.01 (10 mA)
+    pub current: f64,
+    /// Volts per count of the pack voltage register, default 0.1
+    pub voltage: f64,
+    /// Ah per count of the remaining charge and capacity registers, default 0.001 (1 mAh)
+    pub charge: f64,
+    /// Volts per count of the cell voltage registers, default 0.1
+    pub cell_voltage: f64,
+}
+
+impl Default for Scales {
+    fn default() -> Self {
+        Self { current: 0.01, voltage: 0.1, charge: 0.001, cell_voltage: 0.1 }
+    }
+}
+
+/// Describes model specific differences in how a battery lays out its registers
+#[derive(Clone, Debug, Default)]
+pub struct RegisterMap {
+    pub temperature_encoding: TemperatureEncoding,
+    /// Units of the current, voltage, charge and cell voltage registers
+    pub scales: Scales,
+    /// Swap the two bytes of the current register before decoding it
+    ///
+    /// The RBT series batteries this crate was developed against send the current big-endian
+    /// like every other register, as Core series batteries are reported to, so this is off by
+    /// default. It exists for firmware that byte-swaps the register,
+    /// which shows up as wildly wrong currents (e.g. 0.01 A reading as 2.56 A).
+    pub swap_current_bytes: bool,
+    /// The byte order of every register, applied to all reads and writes
+    ///
+    /// Big-endian by default, as modbus specifies. `Battery::with_detected_byte_order` sets it
+    /// for firmware which swaps the bytes of all of its registers. `swap_current_bytes` is
+    /// applied on top, for firmware which only swaps the current.
+    pub byte_order: ByteOrder,
+    /// Registers holding separate charge and discharge currents, as u16s in units of 10 mA
+    ///
+    /// Renogy smart lithium batteries report one signed current instead, so these are `None` by
+    /// default and the split currents are derived from `Battery::current`.
+    pub charge_current: Option<u16>,
+    pub discharge_current: Option<u16>,
+    /// Register holding the BMS's estimated time until empty, as a u16 in minutes
+    ///
+    /// Not documented in the Renogy smart lithium protocol, so this is `None` by default and the
+    /// time is estimated from the remaining charge and current instead
+    pub time_to_empty: Option<u16>,
+    /// Register holding the BMS's estimated time until full, as a u16 in minutes
+    ///
+    /// Not documented in the Renogy smart lithium protocol, so this is `None` by default and the
+    /// time is estimated from the missing charge and current instead
+    pub time_to_full: Option<u16>,
+    /// First of four registers holding the highest cell voltage, its cell number, the lowest
+    /// cell voltage, and its cell number
+    ///
+    /// Voltages are u16s in units of 100 mV like the cell voltage registers, and cell numbers
+    /// count from 1. Not documented in the Renogy smart lithium protocol, so this is `None` by
+    /// default and the extremes are found by reading every cell.
+    pub cell_extremes: Option<u16>,
+    /// Registers which must be read as input registers (function 0x04)
+    ///
+    /// Renogy smart lithium batteries keep everything in holding registers (function 0x03),
+    /// but some models split the layout, e.g. with voltage and current in input registers. These
+    /// are read with function 0x04. A block read spanning both tables is split into one read
+    /// per table. Empty by default.
+    pub input_registers: Vec<RegAddr>,
+}
+
+/// Which modbus table a register is read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterKind {
+    /// Read with function 0x03
+    Holding,
+    /// Read with function 0x04
+    Input,
+}
+
+impl RegisterMap {
+    /// Which table the register word at `addr` is read from
+    ///
+    /// Every word of a multi-word register listed in `input_registers` counts as an input
+    /// register, e.g. both words of `RegAddr::RemainingCharge`.
+    pub fn register_kind(&self, addr: u16) -> RegisterKind {
+        let listed = |reg: &RegAddr| (*reg as u16..(*reg as u16).saturating_add(reg.size())).contains(&addr);
+        if self.input_registers.iter().any(listed) {
+            RegisterKind::Input
+        } else {
+            RegisterKind::Holding
+        }
+    }
+}
+
+/// How register reads are timed out and retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadPolicy {
+    /// How long to wait for each response
+    pub timeout: Duration,
+    /// How many times to try a read before giving up, including the first attempt
+    pub max_attempts: u32,
+    /// How long to wait between attempts, with the port released
+    pub backoff: Duration,
+}
+
+impl Default for ReadPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(200),
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+/// A battery on a bus
+///
+/// All methods take `&self`, and any state the battery keeps between reads lives behind a
+/// shared lock, so a `Battery` is `Send + Sync` and can be used from several tasks at once.
+/// Clones share both the port and that state. The state lock is never held across an await, so
+/// it cannot deadlock against the port lock.
+#[derive(Clone)]
+pub struct Battery {
+    port: PortSource,
+    addr: u8,
+    name: Option<String>,
+    wakeup: bool,
+    map: RegisterMap,
+    policy: ReadPolicy,
+    state: Arc<std::sync::Mutex<SharedState>>,
+    clock: Arc<dyn Clock>,
+    check_consistency: bool,
+    #[cfg(feature = "write")]
+    dry_run: bool,
+}
+
+/// Several connections to one Modbus TCP gateway, for concurrent reads
+///
+/// Each transaction takes whichever connection is idle, or queues on the next one in turn if
+/// none are, so up to `len()` transactions are in flight at once. Only use this if the gateway
+/// really handles concurrent requests: a gateway onto a single RS485 line serializes them
+/// anyway, and some drop extra connections. Rate limits and transaction hooks are per
+/// connection, so set them on each of `ports()`.
+#[cfg(feature = "tcp")]
+pub struct PortPool {
+    first: Arc<Mutex<Port>>,
+    others: Vec<Arc<Mutex<Port>>>,
+    next: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(feature = "tcp")]
+impl PortPool {
+    /// Open `size` connections to a Modbus TCP gateway, at least one
+    pub async fn connect_tcp(addr: std::net::SocketAddr, size: usize) -> Result<Arc<Self>> {
+        let first = Arc::new(Mutex::new(Port::connect_tcp(addr).await?));
+        let mut others = Vec::with_capacity(size.saturating_sub(1));
+        for _ in 1..size {
+            others.push(Arc::new(Mutex::new(Port::connect_tcp(addr).await?)));
+        }
+        Ok(Arc::new(Self { first, others, next: Default::default() }))
+    }
+
+    /// The number of connections
+    pub fn len(&self) -> usize {
+        self.others.len() + 1
+    }
+
+    /// Always false, a pool has at least one connection
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The connections, e.g. to set a rate limit on each
+    pub fn ports(&self) -> impl Iterator<Item = &Arc<Mutex<Port>>> {
+        std::iter::once(&self.first).chain(&self.others)
+    }
+
+    /// Take an idle connection, or wait for the next one in turn
+    async fn lock(&self) -> MutexGuard<'_, Port> {
+        if let Some(port) = self.ports().find_map(|port| port.try_lock().ok()) {
+            return port;
+        }
+        let next = self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % self.len();
+        let port = match next.checked_sub(1) {
+            Some(i) => self.others.get(i).unwrap_or(&self.first),
+            None => &self.first,
+        };
+        port.lock().await
+    }
+
+    /// How long since a read from `slave` last succeeded on any of the connections
+    ///
+    /// Each connection only sees the reads made over it, so a battery is only idle if it has been
+    /// idle on all of them.
+    async fn idle_time(&self, slave: u8) -> Duration {
+        let mut idle = Duration::MAX;
+        for port in self.ports() {
+            idle = idle.min(port.lock().await.idle_time(slave));
+        }
+        idle
+    }
+
+    /// Take an idle connection, if there is one
+    fn try_lock_owned(&self) -> Option<OwnedMutexGuard<Port>> {
+        self.ports().find_map(|port| port.clone().try_lock_owned().ok())
+    }
+}
+
+/// How a battery gets access to its port
+#[derive(Clone)]
+enum PortSource {
+    /// A port shared with other batteries, locked for each transaction
+    Shared(Arc<Mutex<Port>>),
+    /// A port which has already been locked for a sequence of transactions
+    Held(Arc<Mutex<OwnedMutexGuard<Port>>>),
+    /// A pool of gateway connections, one taken for each transaction
+    #[cfg(feature = "tcp")]
+    Pool(Arc<PortPool>),
+}
+
+impl PortSource {
+    async fn lock(&self) -> PortGuard<'_> {
+        match self {
+            PortSource::Shared(port) => PortGuard::Shared(port.lock().await),
+            PortSource::Held(port) => PortGuard::Held(port.lock().await),
+            #[cfg(feature = "tcp")]
+            PortSource::Pool(pool) => PortGuard::Shared(pool.lock().await),
+        }
+    }
+
+    /// How long since a read from `slave` last succeeded, see `Port::idle_time`
+    async fn idle_time(&self, slave: u8) -> Duration {
+        match self {
+            #[cfg(feature = "tcp")]
+            PortSource::Pool(pool) => pool.idle_time(slave).await,
+            PortSource::Shared(_) | PortSource::Held(_) => self.lock().await.idle_time(slave),
+        }
+    }
+}
+
+enum PortGuard<'a> {
+    Shared(MutexGuard<'a, Port>),
+    Held(MutexGuard<'a, OwnedMutexGuard<Port>>),
+}
+
+impl std::ops::Deref for PortGuard<'_> {
+    type Target = Port;
+
+    fn deref(&self) -> &Port {
+        match self {
+            PortGuard::Shared(port) => port,
+            PortGuard::Held(port) => port,
+        }
+    }
+}
+
+impl std::ops::DerefMut for PortGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Port {
+        match self {
+            PortGuard::Shared(port) => port,
+            PortGuard::Held(port) => port,
+        }
+    }
+}
+
+/// Mutable state kept between reads of a battery
+#[derive(Default)]
+struct SharedState {
+    /// The result of the last successful `read_all`, and when it completed
+    last_state: Option<(std::time::Instant, BatteryState)>,
+    /// The result of the first successful `read_info`
+    info: Option<BatteryInfo>,
+    /// Writes suppressed by dry run mode, see `Battery::take_suppressed_writes`
+    #[cfg(feature = "write")]
+    suppressed_writes: Vec<SuppressedWrite>,
+}
+
+/// A write which `Battery::with_dry_run` kept off the bus
+#[cfg(feature = "write")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SuppressedWrite {
+    /// The first register written
+    pub addr: u16,
+    /// The values for consecutive registers from `addr`, before any byte swapping
+    pub values: Vec<u16>,
+}
+
+/// How long a battery must be silent before a timeout is blamed on it sleeping
+const WAKEUP_IDLE: Duration = Duration::from_secs(30);
+
+/// How long to give a battery to wake up after the wake-up read
+const WAKEUP_DELAY: Duration = Duration::from_millis(200);
+
+/// The shortest interval the polling functions read at
+///
+/// `poll_stream`, `spawn_soc_tracker`, `serve` and the other pollers raise shorter intervals,
+/// including zero, to this.
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A ticker for polling every `interval`, raised to at least `MIN_POLL_INTERVAL`
+pub(crate) fn poll_ticker(interval: Duration) -> tokio::time::Interval {
+    let mut ticker = tokio::time::interval(interval.max(MIN_POLL_INTERVAL));
+    // If a read overruns the interval, carry on from there rather than bursting to catch up
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker
+}
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// The battery didn't answer the transaction numbered `sequence` in time, see `Port::sequence`
+    Timeout { sequence: u64 },
+    NoDevice(String),
+    /// The serial port is held by another process, or we lack permission to open it
+    PortBusy(String),
+    InvalidInput(String),
+    Unknown(String),
+    Io(std::io::ErrorKind),
+    /// The battery responded with something we could not decode
+    ///
+    /// `sequence` numbers the transaction, if the problem was caught while reading the response
+    /// rather than when decoding a value from it later.
+    InvalidResponse { sequence: Option<u64>, message: String },
+    /// The battery's register map doesn't provide the named quantity
+    Unsupported(&'static str),
+    /// The battery responded with a modbus exception
+    Exception(Exception),
+    /// A register read back different after `Battery::write_register_verified` wrote it
+    WriteVerificationFailed { addr: u16, written: u16, read: u16 },
+    /// The remaining charge is well above the capacity, see `Battery::with_consistency_check`
+    InconsistentState { remaining_charge: f64, capacity: f64 },
+}
+
+impl Error {
+    /// True if a retry of the failed transaction might succeed
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::Timeout { .. }
+                | Error::Io(_)
+                | Error::InvalidResponse { .. }
+                | Error::Exception(Exception::ServerDeviceBusy)
+        )
+    }
+
+    /// True if the error indicates the battery does not support the requested quantity
+    ///
+    /// This covers both quantities missing from the register map, and batteries answering
+    /// with an illegal address/function exception.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            Error::Unsupported(_) | Error::Exception(Exception::IllegalDataAddress | Exception::IllegalFunction)
+        )
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        // tokio-modbus reports exception responses as an io error wrapping a (private)
+        // ExceptionResponse, whose message ends with the exception description
+        const EXCEPTIONS: [Exception; 9] = [
+            Exception::IllegalFunction,
+            Exception::IllegalDataAddress,
+            Exception::IllegalDataValue,
+            Exception::ServerDeviceFailure,
+            Exception::Acknowledge,
+            Exception::ServerDeviceBusy,
+            Exception::MemoryParityError,
+            Exception::GatewayPathUnavailable,
+            Exception::GatewayTargetDevice,
+        ];
+        if let Some(inner) = value.get_ref() {
+            let message = inner.to_string();
+            if let Some(exception) = EXCEPTIONS.into_iter().find(|e| message.ends_with(&e.to_string())) {
+                return Error::Exception(exception);
+            }
+        }
+        Error::Io(value.kind())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Get the word at register `addr` out of a block read starting at `base`
+fn block_word(block: &[u16], base: u16, addr: u16) -> Result<u16> {
+    addr.checked_sub(base)
+        .and_then(|offset| block.get(offset as usize))
+        .copied()
+        .ok_or_else(|| Error::InvalidResponse {
+            sequence: None,
+            message: format!("register {:#06x} missing from block read", addr),
+        })
+}
+
+/// Decode an ASCII string packed two characters per register, high byte first
+///
+/// The string ends at the first NUL, and surrounding padding is trimmed
+fn decode_string(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).take_while(|b| *b != 0).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+/// Turn an unsupported error into `None`, for reading quantities not all models provide
+fn optional<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.is_unsupported() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
+/// The registers this crate knows the address of
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegAddr {
+    Current = 0x13b2,
+    Voltage = 0x13b3,
+    RemainingCharge = 0x13b4,
+    Capacity = 0x13b6,
+    CycleNumber = 0x13b8,
+    ChargeVoltageLimit = 0x13b9,
+    ChargeCurrentLimit = 0x13bb,
+    DischargeCurrentLimit = 0x13bc,
+    CellCount = 0x1388,
+    CellVoltage1 = 0x1389,
+    CellVoltage2 = 0x138a,
+    CellVoltage3 = 0x138b,
+    CellVoltage4 = 0x138c,
+    TempCount = 0x1399,
+    CellTemp1 = 0x139a,
+    CellTemp2 = 0x139b,
+    CellTemp3 = 0x139c,
+    CellTemp4 = 0x139d,
+    HeaterLevel = 0x13ef,
+    Status1 = 0x13f2,
+    SerialNumber = 0x13f6,
+    Model = 0x1402,
+    ManufacturerName = 0x140c,
+    CellOverVoltageLimit = 0x1450,
+    CellUnderVoltageLimit = 0x1453,
+    ChargeOverTempLimit = 0x1454,
+    ChargeUnderTempLimit = 0x1457,
+    PackOverVoltageLimit = 0x145b,
+    PackUnderVoltageLimit = 0x145e,
+    DischargeOverTempLimit = 0x145f,
+    DischargeUnderTempLimit = 0x1462,
+}
+
+impl RegAddr {
+    /// Every known register
+    pub const ALL: [RegAddr; 31] = [
+        RegAddr::Current,
+        RegAddr::Voltage,
+        RegAddr::RemainingCharge,
+        RegAddr::Capacity,
+        RegAddr::CycleNumber,
+        RegAddr::ChargeVoltageLimit,
+        RegAddr::ChargeCurrentLimit,
+        RegAddr::DischargeCurrentLimit,
+        RegAddr::CellCount,
+        RegAddr::CellVoltage1,
+        RegAddr::CellVoltage2,
+        RegAddr::CellVoltage3,
+        RegAddr::CellVoltage4,
+        RegAddr::TempCount,
+        RegAddr::CellTemp1,
+        RegAddr::CellTemp2,
+        RegAddr::CellTemp3,
+        RegAddr::CellTemp4,
+        RegAddr::HeaterLevel,
+        RegAddr::Status1,
+        RegAddr::SerialNumber,
+        RegAddr::Model,
+        RegAddr::ManufacturerName,
+        RegAddr::CellOverVoltageLimit,
+        RegAddr::CellUnderVoltageLimit,
+        RegAddr::ChargeOverTempLimit,
+        RegAddr::ChargeUnderTempLimit,
+        RegAddr::PackOverVoltageLimit,
+        RegAddr::PackUnderVoltageLimit,
+        RegAddr::DischargeOverTempLimit,
+        RegAddr::DischargeUnderTempLimit,
+    ];
+
+    /// The number of registers the value occupies
+    pub fn size(&self) -> u16 {
+        match self {
+            RegAddr::RemainingCharge | RegAddr::Capacity => 2,
+            RegAddr::SerialNumber | RegAddr::Model => 8,
+            RegAddr::ManufacturerName => 10,
+            _ => 1,
+        }
+    }
+}
+
+/// How a register responded to `Battery::probe_registers`
+#[derive(Clone, Debug)]
+pub enum ProbeStatus {
+    /// The register was read and its value is plausible
+    Ok,
+    /// The battery doesn't have the register
+    Unsupported,
+    /// The register was read, but its value is outside the range expected for the quantity
+    Implausible,
+    /// The read failed for another reason, e.g. a timeout
+    Failed(Error),
+}
+
+/// The result of probing one register
+#[derive(Clone, Debug)]
+pub struct RegisterProbe {
+    pub reg: RegAddr,
+    /// The raw register values, if the read succeeded
+    pub raw: Option<Vec<u16>>,
+    pub status: ProbeStatus,
+}
+
+/// A field which failed a cross-check in `Battery::validate_map`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapMismatch {
+    pub field: Field,
+    /// What looked wrong, for showing to a human
+    pub reason: String,
+}
+
+/// The result of `Battery::validate_map`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MapValidation {
+    pub mismatches: Vec<MapMismatch>,
+}
+
+impl MapValidation {
+    /// True if every cross-check passed
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl Battery {
+    /// Create a battery at slave address `addr`
+    ///
+    /// Batteries on the same bus must be given clones of the same `port`, see
+    /// `Port::open_shared`.
+    pub fn new(port: Arc<Mutex<Port>>, addr: u8) -> Self {
+        Self::from_source(PortSource::Shared(port), addr)
+    }
+
+    fn from_source(port: PortSource, addr: u8) -> Self {
+        Self {
+            port,
+            addr,
+            name: None,
+            wakeup: false,
+            map: RegisterMap::default(),
+            policy: ReadPolicy::default(),
+            state: Default::default(),
+            clock: Arc::new(SystemClock),
+            check_consistency: false,
+            #[cfg(feature = "write")]
+            dry_run: false,
+        }
+    }
+
+    /// Create a battery at unit identifier `addr`, reached through a pool of gateway connections
+    ///
+    /// Batteries sharing `pool` read concurrently, up to one transaction per connection. See
+    /// `PortPool` for when that is safe.
+    #[cfg(feature = "tcp")]
+    pub fn new_pooled(pool: Arc<PortPool>, addr: u8) -> Self {
+        Self::from_source(PortSource::Pool(pool), addr)
+    }
+
+    /// The slave address of this battery
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    /// Give this battery a human readable name, e.g. "garage_pack", for logging
+    ///
+    /// The crate's own log messages (with the `log` feature) use `label`. Errors and the items of
+    /// `merged_poll_stream` only carry the slave address, so name them with `label` when
+    /// reporting them.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The name given with `with_name`, if any
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// A label for log messages: the name and slave address, e.g. "garage_pack (247)", or just
+    /// the address if the battery has no name
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.addr),
+            None => self.addr.to_string(),
+        }
+    }
+
+    /// Lock the shared state
+    ///
+    /// The state is always left consistent, so a panic in another thread holding the lock
+    /// doesn't make it unusable
+    fn shared(&self) -> std::sync::MutexGuard<'_, SharedState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The result of the last successful `read_all`, and when it was read
+    pub fn last_state(&self) -> Option<(std::time::Instant, BatteryState)> {
+        self.shared().last_state
+    }
+
+    /// Use a non-default timeout and retry policy for reads from this battery
+    pub fn with_read_policy(mut self, policy: ReadPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Take timestamps from `clock` instead of the system clock
+    ///
+    /// Covers `last_state` and `spawn_soc_tracker`'s integration steps. The idle time behind
+    /// `with_wakeup` is kept by the port on its own clock, see `Port::set_clock`, or for a pooled
+    /// battery by each of the pool's connections. Mostly for tests, with a `MockClock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Wake the battery's BMS if it appears to have gone to sleep
+    ///
+    /// Some BMSes sleep when idle and miss the first request after waking. With this enabled, a
+    /// read which times out when the battery hasn't answered for a while is followed by a
+    /// wake-up read, a short pause, and one extra attempt before the timeout is reported.
+    pub fn with_wakeup(mut self, wakeup: bool) -> Self {
+        self.wakeup = wakeup;
+        self
+    }
+
+    /// Make `read_all` fail with `Error::InconsistentState` when the readings don't add up
+    ///
+    /// Off by default, in which case `BatteryState::state_of_charge` just clamps. With this
+    /// enabled, a state failing `BatteryState::charge_consistent` is an error instead.
+    pub fn with_consistency_check(mut self, check: bool) -> Self {
+        self.check_consistency = check;
+        self
+    }
+
+    /// Suppress writes, recording each one instead
+    ///
+    /// For previewing what a commissioning tool would change. Every write method returns
+    /// `Ok(())` without touching the bus, and `write_register_verified` skips its read back.
+    /// Reads are unaffected. Collect the suppressed writes with `take_suppressed_writes`; with
+    /// the `log` feature each is also logged at info level as it happens.
+    #[cfg(feature = "write")]
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The writes suppressed in dry run mode since the last call, oldest first
+    #[cfg(feature = "write")]
+    pub fn take_suppressed_writes(&self) -> Vec<SuppressedWrite> {
+        std::mem::take(&mut self.shared().suppressed_writes)
+    }
+
+    /// Record a write if dry run mode suppresses it, returning true if it was suppressed
+    #[cfg(feature = "write")]
+    fn suppress_write(&self, addr: u16, values: &[u16]) -> bool {
+        if self.dry_run {
+            #[cfg(feature = "log")]
+            log::info!(
+                "dry run: suppressed write to battery {}: register {:#06x} = {:#06x?}",
+                self.label(),
+                addr,
+                values
+            );
+            self.shared().suppressed_writes.push(SuppressedWrite { addr, values: values.to_vec() });
+        }
+        self.dry_run
+    }
+
+    /// Use a non-default register map for this battery
+    pub fn with_register_map(mut self, map: RegisterMap) -> Self {
+        self.map = map;
+        self
+    }
+
+    pub async fn read_register(&self, addr: u16, size: u16) -> Result<Vec<u16>> {
+        let mut words = vec![0; size as usize];
+        self.read_register_into(addr, &mut words).await?;
+        Ok(words)
+    }
+
+    /// Read `size` consecutive registers starting at `addr` and decode them with `decode`
+    ///
+    /// An escape hatch for register formats this crate doesn't wrap. See
+    /// `examples/read_decoded.rs`.
+    pub async fn read_decoded<T>(
+        &self,
+        addr: u16,
+        size: u16,
+        decode: impl FnOnce(&[u16]) -> T,
+    ) -> Result<T> {
+        let words = self.read_register(addr, size).await?;
+        Ok(decode(&words))
+    }
+
+    /// Read `buf.len()` consecutive registers starting at `addr` into `buf`
+    ///
+    /// This avoids allocating on every read in polling loops. Returns the number of registers
+    /// read, which is always `buf.len()` on success.
+    pub async fn read_register_into(&self, addr: u16, buf: &mut [u16]) -> Result<usize> {
+        self.read_register_into_with_policy(addr, buf, &self.policy).await
+    }
+
+    async fn read_register_into_with_policy(&self, addr: u16, buf: &mut [u16], policy: &ReadPolicy) -> Result<usize> {
+        let mut attempt = 1;
+        let mut may_wake = self.wakeup && self.port.idle_time(self.addr).await >= WAKEUP_IDLE;
+        loop {
+            match self.try_read_register_into(addr, buf, policy.timeout).await {
+                Err(Error::Timeout { .. }) if may_wake => {
+                    may_wake = false;
+                    #[cfg(feature = "log")]
+                    log::info!("battery {} timed out after being idle, waking it", self.label());
+                    self.wake(policy.timeout).await;
+                }
+                Err(e) if attempt < policy.max_attempts && e.is_transient() => {
+                    #[cfg(feature = "log")]
+                    log::debug!("retrying read from battery {} at {:#06x} after {:?}", self.label(), addr, e);
+                    attempt += 1;
+                    tokio::time::sleep(policy.backoff).await;
+                    if !matches!(e, Error::Exception(_)) {
+                        // Drop whatever is left of a bad response, so the retry starts clean
+                        let _ = self.port.lock().await.flush_input();
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a throwaway read to wake the BMS, then give it time to come up
+    async fn wake(&self, read_timeout: Duration) {
+        let mut buf = [0];
+        let _ = self.try_read_register_into(RegAddr::Voltage as u16, &mut buf, read_timeout).await;
+        tokio::time::sleep(WAKEUP_DELAY).await;
+    }
+
+    /// Make one attempt at a read
+    ///
+    /// The span is split into consecutive runs of holding and input registers, each read in its
+    /// own transaction, so a block read never takes a register from the wrong table.
+    async fn try_read_register_into(&self, addr: u16, buf: &mut [u16], read_timeout: Duration) -> Result<usize> {
+        let size = u16::try_from(buf.len())
+            .map_err(|_| Error::InvalidInput(format!("cannot read {} registers at once", buf.len())))?;
+        if addr.checked_add(size.saturating_sub(1)).is_none() {
+            return Err(Error::InvalidInput(format!(
+                "{} registers from {:#06x} runs past the end of the address space",
+                size, addr
+            )));
+        }
+        let mut offset = 0;
+        while offset < size {
+            let start = addr + offset;
+            let kind = self.map.register_kind(start);
+            let len = (offset..size).take_while(|i| self.map.register_kind(addr + i) == kind).count();
+            let run = buf
+                .get_mut(offset as usize..offset as usize + len)
+                .ok_or_else(|| Error::InvalidInput(format!("cannot read {} registers at once", size)))?;
+            self.try_read_run(start, run, kind, read_timeout).await?;
+            offset += len as u16;
+        }
+        Ok(buf.len())
+    }
+
+    /// Read `buf.len()` registers from one table in a single transaction
+    async fn try_read_run(&self, addr: u16, buf: &mut [u16], kind: RegisterKind, read_timeout: Duration) -> Result<()> {
+        let size = buf.len() as u16;
+        let op = match kind {
+            RegisterKind::Holding => Operation::ReadHoldingRegisters { addr, count: size },
+            RegisterKind::Input => Operation::ReadInputRegisters { addr, count: size },
+        };
+        let mut port = self.port.lock().await;
+        let words = port.transact(self.addr, op, read_timeout).await?;
+        if words.len() != buf.len() {
+            return Err(Error::InvalidResponse {
+                sequence: Some(port.sequence()),
+                message: format!("expected {} registers from {:#06x}, got {}", size, addr, words.len()),
+            });
+        }
+        buf.copy_from_slice(&words);
+        if self.map.byte_order == ByteOrder::Swapped {
+            buf.iter_mut().for_each(|w| *w = w.swap_bytes());
+        }
+        Ok(())
+    }
+
+    /// Read `count` consecutive registers starting at `start`, in as many transactions as needed
+    ///
+    /// Modbus allows at most 125 registers per read, so larger ranges are split into chunks.
+    /// Each chunk is a separate transaction, with the same inter-frame delay as any other read,
+    /// and other users of the port may interleave between chunks.
+    pub async fn read_range(&self, start: u16, count: u16) -> Result<Vec<u16>> {
+        const MAX_READ: usize = 125;
+        if start.checked_add(count.saturating_sub(1)).is_none() {
+            return Err(Error::InvalidInput(format!(
+                "{} registers from {:#06x} runs past the end of the address space",
+                count, start
+            )));
+        }
+        let mut words = vec![0; count as usize];
+        for (i, chunk) in words.chunks_mut(MAX_READ).enumerate() {
+            self.read_register_into(start + (i * MAX_READ) as u16, chunk).await?;
+        }
+        Ok(words)
+    }
+
+    /// Read `N` consecutive registers into an array
+    async fn read_array<const N: usize>(&self, addr: u16) -> Result<[u16; N]> {
+        let mut words = [0; N];
+        self.read_register_into(addr, &mut words).await?;
+        Ok(words)
+    }
+
+    /// Write a raw u16 value to a holding register
+    #[cfg(feature = "write")]
+    pub async fn write_register(&self, addr: u16, value: u16) -> Result<()> {
+        if self.suppress_write(addr, &[value]) {
+            return Ok(());
+        }
+        let value = match self.map.byte_order {
+            ByteOrder::BigEndian => value,
+            ByteOrder::Swapped => value.swap_bytes(),
+        };
+        let mut port = self.port.lock().await;
+        port.transact(self.addr, Operation::WriteSingleRegister { addr, value }, self.policy.timeout)
+            .await?;
+        Ok(())
+    }
+
+    /// Write a raw u16 value to a holding register, then read it back to check it took
+    ///
+    /// Some config registers silently ignore out of range values, which a plain
+    /// `write_register` can't detect. Returns `Error::WriteVerificationFailed` if the register
+    /// reads back different.
+    #[cfg(feature = "write")]
+    pub async fn write_register_verified(&self, addr: u16, value: u16) -> Result<()> {
+        self.write_register(addr, value).await?;
+        if self.dry_run {
+            return Ok(());
+        }
+        let read = self.read_u16(addr).await?;
+        if read != value {
+            return Err(Error::WriteVerificationFailed { addr, written: value, read });
+        }
+        Ok(())
+    }
+
+    /// Write raw u16 values to consecutive holding registers in a single transaction
+    #[cfg(feature = "write")]
+    pub async fn write_registers(&self, addr: u16, values: &[u16]) -> Result<()> {
+        if self.suppress_write(addr, values) {
+            return Ok(());
+        }
+        let swapped: Vec<u16>;
+        let values = match self.map.byte_order {
+            ByteOrder::BigEndian => values,
+            ByteOrder::Swapped => {
+                swapped = values.iter().map(|v| v.swap_bytes()).collect();
+                &swapped
+            }
+        };
+        let mut port = self.port.lock().await;
+        port.transact(self.addr, Operation::WriteMultipleRegisters { addr, values }, self.policy.timeout)
+            .await?;
+        Ok(())
+    }
+
+    /// Read a raw u16 value from a register
+    ///
+    /// Modbus transfers each register big-endian (high byte first), and tokio-modbus hands
+    /// us the already assembled word, so no swapping is needed here.
+    pub async fn read_u16(&self, addr: u16) -> Result<u16> {
+        let [value] = self.read_array(addr).await?;
+        Ok(value)
+    }
+
+    /// Read a raw i16 value from a register
+    ///
+    /// The register is a big-endian two's complement word, e.g. 0xff9c reads as -100
+    pub async fn read_i16(&self, addr: u16) -> Result<i16> {
+        let [value] = self.read_array(addr).await?;
+        Ok(value as i16)
+    }
+
+    /// Read a raw u32 value from a register
+    ///
+    /// The value spans two registers, with the high word in the first (lower addressed)
+    /// register, e.g. [0x0001, 0x86a0] reads as 100000
+    pub async fn read_u32(&self, addr: u16) -> Result<u32> {
+        let [high, low] = self.read_array(addr).await?;
+        Ok(low as u32 + ((high as u32) << 16))
+    }
+
+    /// Get the instantaneous battery current in Amps
+    ///
+    /// Current is negative when discharging, positive when charging
+    pub async fn current(&self) -> Result<f64> {
+        // Signed word, by default in units of 10 mA: 0xff9c (-100) reads as -1.00 A
+        let mut raw = self.read_i16(RegAddr::Current as u16).await?;
+        if self.map.swap_current_bytes {
+            raw = raw.swap_bytes();
+        }
+        Ok(raw as f64 * self.map.scales.current)
+    }
+
+    /// Read the voltage in V and current in Amps, for high rate control loops
+    ///
+    /// The two registers are adjacent, so this is one transaction: about 30 ms at 9600 baud,
+    /// made up of the 10 ms inter-frame delay and ~18 ms on the wire, against around 250 ms for
+    /// the eight transactions of `read_all`. The current is decoded as in `current()`.
+    pub async fn read_vi(&self) -> Result<(f64, f64)> {
+        let [raw_current, raw_voltage]: [u16; 2] = self.read_array(RegAddr::Current as u16).await?;
+        let mut raw_current = raw_current as i16;
+        if self.map.swap_current_bytes {
+            raw_current = raw_current.swap_bytes();
+        }
+        Ok((raw_voltage as f64 * self.map.scales.voltage, raw_current as f64 * self.map.scales.current))
+    }
+
+    /// Get the current flowing into the battery in Amps
+    ///
+    /// Reads `RegisterMap::charge_current` if set, otherwise this is the positive part of
+    /// `current()`. Always >= 0.
+    pub async fn charge_current(&self) -> Result<f64> {
+        match self.map.charge_current {
+            Some(addr) => Ok(self.read_u16(addr).await? as f64 * self.map.scales.current),
+            None => Ok(self.current().await?.max(0.0)),
+        }
+    }
+
+    /// Get the current flowing out of the battery in Amps
+    ///
+    /// Reads `RegisterMap::discharge_current` if set, otherwise this is the negative part of
+    /// `current()`. Always >= 0.
+    pub async fn discharge_current(&self) -> Result<f64> {
+        match self.map.discharge_current {
+            Some(addr) => Ok(self.read_u16(addr).await? as f64 * self.map.scales.current),
+            None => Ok((-self.current().await?).max(0.0)),
+        }
+    }
+
+    /// Read the charge voltage the BMS requests, in V
+    ///
+    /// The constant voltage setpoint for an external charger, from the charge voltage limit at
+    /// 0x13b9 in units of 0.1 V. It sits below the cell count times the cell over voltage
+    /// threshold in `protection_thresholds`, so a charger holding it finishes the charge without
+    /// tripping the over voltage protection. Batteries without the register answer with an
+    /// exception, see `Error::is_unsupported`.
+    pub async fn target_charge_voltage(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::ChargeVoltageLimit as u16).await?;
+        Ok(raw as f64 * 0.1)
+    }
+
+    /// Read the charge and discharge current limits in force
+    ///
+    /// The BMS lowers these from their nominal values when the cells are cold or hot, so a
+    /// charge controller should stay below `charge_limit` rather than trip the protection. The
+    /// derating curve itself isn't exposed over modbus.
+    pub async fn active_limits(&self) -> Result<CurrentLimits> {
+        // Unsigned words, in the units of the current register
+        let [charge, discharge]: [u16; 2] = self.read_array(RegAddr::ChargeCurrentLimit as u16).await?;
+        let scale = self.map.scales.current;
+        Ok(CurrentLimits { charge_limit: charge as f64 * scale, discharge_limit: discharge as f64 * scale })
+    }
+
+    /// Get the battery voltage in Volts
+    ///
+    /// This is the BMS's own pack voltage measurement at 0x13b3, taken across the whole pack. It
+    /// is the one to trust for the voltage at the terminals; `computed_pack_voltage` sums the
+    /// cell measurements instead, for cross-checking.
+    pub async fn voltage(&self) -> Result<f64> {
+        // Unsigned word, by default in units of 100 mV: 0x0085 (133) reads as 13.3 V
+        let raw = self.read_u16(RegAddr::Voltage as u16).await?;
+        Ok(raw as f64 * self.map.scales.voltage)
+    }
+
+    /// Get the pack voltage in Volts as the sum of the reported cells' voltages
+    ///
+    /// Reads the cell count, then that many cell voltages in one read. The cell voltages are the
+    /// better guide to the cells themselves, but exclude the drop across the BMS and busbars, and
+    /// each carries its own rounding. A difference from `voltage` which persists beyond that,
+    /// see `BatteryState::pack_vs_cells_discrepancy`, points to a sensing fault.
+    pub async fn computed_pack_voltage(&self) -> Result<f64> {
+        let cell_count = self.cell_count().await?;
+        Ok(self.cell_voltages_n(cell_count as usize).await?.iter().sum())
+    }
+
+    /// Read the cell count, rejecting counts beyond the cell voltage registers
+    async fn cell_count(&self) -> Result<u16> {
+        let count = self.read_u16(RegAddr::CellCount as u16).await?;
+        if count > MAX_CELLS {
+            return Err(Error::InvalidResponse {
+                sequence: None,
+                message: format!("cell count {} exceeds the {} cell voltage registers", count, MAX_CELLS),
+            });
+        }
+        Ok(count)
+    }
+
+    /// Get state of charge
+    ///
+    /// Returns the estimated remaining charge in Ah
+    pub async fn remaining_charge(&self) -> Result<f64> {
+        // High word first, by default in units of mAh: [0x0001, 0x86a0] (100000) reads as 100.0 Ah
+        let raw = self.read_u32(RegAddr::RemainingCharge as u16).await?;
+        Ok(raw as f64 * self.map.scales.charge)
+    }
+
+    /// Get the total battery capacity
+    ///
+    /// Returns the battery capacity (when fully charged) in Ah, read from 0x13b6
+    pub async fn capacity(&self) -> Result<f64> {
+        // Same encoding as remaining_charge
+        let raw = self.read_u32(RegAddr::Capacity as u16).await?;
+        Ok(raw as f64 * self.map.scales.charge)
+    }
+
+    /// Get the battery cycle counter value
+    pub async fn cycle_number(&self) -> Result<u16> {
+        self.read_u16(RegAddr::CycleNumber as u16).await
+    }
+
+    /// Get individual cell voltage 1 in V
+    pub async fn cell_voltage_1(&self) -> Result<f64> {
+        // Unsigned word, by default in units of 100 mV: 0x0021 (33) reads as 3.3 V. The other
+        // cells match.
+        let raw = self.read_u16(RegAddr::CellVoltage1 as u16).await?;
+        Ok(raw as f64 * self.map.scales.cell_voltage)
+    }
+
+    /// Get individual cell voltage 2 in V
+    pub async fn cell_voltage_2(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::CellVoltage2 as u16).await?;
+        Ok(raw as f64 * self.map.scales.cell_voltage)
+    }
+
+    /// Get individual cell voltage 3 in V
+    pub async fn cell_voltage_3(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::CellVoltage3 as u16).await?;
+        Ok(raw as f64 * self.map.scales.cell_voltage)
+    }
+
+    /// Get individual cell voltage 4 in V
+    pub async fn cell_voltage_4(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::CellVoltage4 as u16).await?;
+        Ok(raw as f64 * self.map.scales.cell_voltage)
+    }
+
+    /// Read how many cell voltages and temperatures the battery reports
+    ///
+    /// Each count immediately precedes its block of sensor registers
+    pub async fn sensor_layout(&self) -> Result<SensorLayout> {
+        Ok(SensorLayout {
+            cell_count: self.read_u16(RegAddr::CellCount as u16).await?,
+            temp_count: self.read_u16(RegAddr::TempCount as u16).await?,
+        })
+    }
+
+    /// Compare the reported cell count against the cells which have plausible voltages
+    ///
+    /// Reads all 16 cell voltage registers, in one read if the battery allows it and one by one
+    /// if not, and counts those between 2.0 and 4.0 V. A reported cell without a plausible
+    /// voltage is dead or disconnected; plausible voltages beyond the reported count point to a
+    /// misconfigured BMS.
+    pub async fn verify_cell_count(&self) -> Result<CellCountCheck> {
+        let reported = self.read_u16(RegAddr::CellCount as u16).await?;
+        let base = RegAddr::CellVoltage1 as u16;
+        let cells: Vec<Option<u16>> = match self.read_array::<{ MAX_CELLS as usize }>(base).await {
+            Ok(block) => block.into_iter().map(Some).collect(),
+            Err(e) if e.is_unsupported() => {
+                let mut cells = Vec::with_capacity(MAX_CELLS as usize);
+                for addr in base..base + MAX_CELLS {
+                    cells.push(optional(self.read_u16(addr).await)?);
+                }
+                cells
+            }
+            Err(e) => return Err(e),
+        };
+        let plausible: Vec<bool> = cells
+            .iter()
+            .map(|raw| raw.is_some_and(|raw| (2.0..=4.0).contains(&(raw as f64 * self.map.scales.cell_voltage))))
+            .collect();
+        Ok(CellCountCheck {
+            reported,
+            responding: plausible.iter().filter(|p| **p).count() as u16,
+            implausible: (0..reported as usize).filter(|i| !plausible.get(*i).copied().unwrap_or(false)).collect(),
+        })
+    }
+
+    /// Get all four cell voltages in V with a single read
+    pub async fn cell_voltages_4(&self) -> Result<[f64; 4]> {
+        let raw: [u16; 4] = self.read_array(RegAddr::CellVoltage1 as u16).await?;
+        Ok(raw.map(|v| v as f64 * self.map.scales.cell_voltage))
+    }
+
+    /// Get the first `n` cell voltages in V with a single read
+    ///
+    /// For packs with more than four cells, e.g. the 16 of a 48V pack. `n` should not exceed
+    /// `sensor_layout().cell_count`, and values above `MAX_CELLS`, which would read into the
+    /// temperature registers, are an `Error::InvalidInput`.
+    pub async fn cell_voltages_n(&self, n: usize) -> Result<Vec<f64>> {
+        if n > MAX_CELLS as usize {
+            return Err(Error::InvalidInput(format!("cannot read {} of the {} cell voltages", n, MAX_CELLS)));
+        }
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let mut raw = vec![0; n];
+        self.read_register_into(RegAddr::CellVoltage1 as u16, &mut raw).await?;
+        Ok(raw.into_iter().map(|v| v as f64 * self.map.scales.cell_voltage).collect())
+    }
+
+    /// Get the highest and lowest cell voltages, and which cells they are
+    ///
+    /// Reads `RegisterMap::cell_extremes` if set, which is cheaper on large packs, otherwise
+    /// reads the cell count and then that many cells in one read. A reported count of 0 is an
+    /// `Error::InvalidResponse`.
+    pub async fn cell_extremes(&self) -> Result<CellExtremes> {
+        match self.map.cell_extremes {
+            Some(addr) => {
+                let [max_v, max_cell, min_v, min_cell] = self.read_array(addr).await?;
+                Ok(CellExtremes {
+                    max_v: max_v as f64 * self.map.scales.cell_voltage,
+                    max_idx: max_cell.saturating_sub(1) as usize,
+                    min_v: min_v as f64 * self.map.scales.cell_voltage,
+                    min_idx: min_cell.saturating_sub(1) as usize,
+                })
+            }
+            None => {
+                let cell_count = self.cell_count().await?;
+                CellExtremes::from_cells(&self.cell_voltages_n(cell_count as usize).await?)
+            }
+        }
+    }
+
+    /// Read individual cell temperature 1 in deg C
+    pub async fn cell_temp_1(&self) -> Result<f64> {
+        // With the default encoding, a signed word in units of 0.1 deg C: 0xff9c (-100) reads as
+        // -10.0 C. The other cells match. See `TemperatureEncoding` for the alternative.
+        let raw = self.read_u16(RegAddr::CellTemp1 as u16).await?;
+        Ok(self.map.temperature_encoding.decode(raw))
+    }
+
+    /// Read individual cell temperature 2 in deg C
+    pub async fn cell_temp_2(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::CellTemp2 as u16).await?;
+        Ok(self.map.temperature_encoding.decode(raw))
+    }
+
+    /// Read individual cell temperature 3 in deg C
+    pub async fn cell_temp_3(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::CellTemp3 as u16).await?;
+        Ok(self.map.temperature_encoding.decode(raw))
+    }
+
+    /// Read individual cell temperature 4 in deg C
+    pub async fn cell_temp_4(&self) -> Result<f64> {
+        let raw = self.read_u16(RegAddr::CellTemp4 as u16).await?;
+        Ok(self.map.temperature_encoding.decode(raw))
+    }
+
+    /// Read all four cell temperatures in deg C with a single read
+    pub async fn cell_temps_4(&self) -> Result<[f64; 4]> {
+        let raw: [u16; 4] = self.read_array(RegAddr::CellTemp1 as u16).await?;
+        Ok(raw.map(|v| self.map.temperature_encoding.decode(v)))
+    }
+
+    /// Read all four cell voltages in V and cell temperatures in deg C with a single read
+    ///
+    /// One read spans 0x1389 to 0x139d. The registers between the two blocks hold the voltages
+    /// of cells 5-16 and the temperature count, and are ignored. If the battery refuses the
+    /// span, e.g. because it doesn't implement the unused cell registers, this falls back to two
+    /// separate reads.
+    pub async fn read_cells_and_temps(&self) -> Result<([f64; 4], [f64; 4])> {
+        const SPAN: usize = (RegAddr::CellTemp4 as u16 - RegAddr::CellVoltage1 as u16 + 1) as usize;
+        let base = RegAddr::CellVoltage1 as u16;
+        let block: [u16; SPAN] = match self.read_array(base).await {
+            Ok(block) => block,
+            Err(e) if e.is_unsupported() => return Ok((self.cell_voltages_4().await?, self.cell_temps_4().await?)),
+            Err(e) => return Err(e),
+        };
+        let word = |addr: RegAddr| block_word(&block, base, addr as u16);
+        let voltage = |addr| word(addr).map(|v| v as f64 * self.map.scales.cell_voltage);
+        let temp = |addr| word(addr).map(|v| self.map.temperature_encoding.decode(v));
+        Ok((
+            [
+                voltage(RegAddr::CellVoltage1)?,
+                voltage(RegAddr::CellVoltage2)?,
+                voltage(RegAddr::CellVoltage3)?,
+                voltage(RegAddr::CellVoltage4)?,
+            ],
+            [temp(RegAddr::CellTemp1)?, temp(RegAddr::CellTemp2)?, temp(RegAddr::CellTemp3)?, temp(RegAddr::CellTemp4)?],
+        ))
+    }
+
+    /// Read heater level in percent
+    ///
+    /// Self-heating batteries run a heater when the cells are too cold to charge. The level is
+    /// the heater's PWM duty cycle, which the register holds as 0-255.
+    pub async fn heater_level(&self) -> Result<f64> {
+        // Unsigned word, 0x00ff (255) reads as 100 %
+        let raw = self.read_u16(RegAddr::HeaterLevel as u16).await?;
+        Ok(raw as f64 * HEATER_LEVEL_SCALE)
+    }
+
+    /// Read whether the BMS charge under-temperature protection is active
+    pub async fn charge_under_temp(&self) -> Result<bool> {
+        // Bit 2 of status 1
+        let raw = self.read_u16(RegAddr::Status1 as u16).await?;
+        Ok(raw & (1 << 2) != 0)
+    }
+
+    /// Read the model string, e.g. "RBT100LFP12S-G1"
+    pub async fn model(&self) -> Result<String> {
+        let raw: [u16; 8] = self.read_array(RegAddr::Model as u16).await?;
+        Ok(decode_string(&raw))
+    }
+
+    /// Read the model string and identify the model
+    pub async fn detect_model(&self) -> Result<RenogyModel> {
+        Ok(RenogyModel::from_model_string(&self.model().await?))
+    }
+
+    /// Work out which byte order the BMS uses from the voltage register
+    ///
+    /// The voltage is decoded both ways, and a byte order is picked if exactly one gives a pack
+    /// voltage between 5 and 70 V. Returns `None` if that is ambiguous, e.g. a raw 0x0101 reads
+    /// the same both ways, or if neither is plausible, which suggests a wrong register map
+    /// rather than a byte order problem.
+    pub async fn detect_byte_order(&self) -> Result<Option<ByteOrder>> {
+        let mut raw = self.read_u16(RegAddr::Voltage as u16).await?;
+        // Judge the word as it came off the wire, not as the configured byte order decoded it
+        if self.map.byte_order == ByteOrder::Swapped {
+            raw = raw.swap_bytes();
+        }
+        let plausible = |raw: u16| (5.0..=70.0).contains(&(raw as f64 * self.map.scales.voltage));
+        Ok(match (plausible(raw), plausible(raw.swap_bytes())) {
+            (true, false) => Some(ByteOrder::BigEndian),
+            (false, true) => Some(ByteOrder::Swapped),
+            _ => None,
+        })
+    }
+
+    /// Detect the byte order with `detect_byte_order` and set `RegisterMap::byte_order` to match
+    ///
+    /// Call once after construction, in place of configuring the byte order by hand. The
+    /// detected order is applied to every register, as firmware which swaps the voltage swaps
+    /// the rest too. If detection is ambiguous the register map's setting is kept.
+    /// `swap_current_bytes` is left alone: a swap of the current register alone doesn't show
+    /// in the voltage, so it still has to be configured.
+    pub async fn with_detected_byte_order(mut self) -> Result<Self> {
+        if let Some(order) = self.detect_byte_order().await? {
+            self.map.byte_order = order;
+        }
+        Ok(self)
+    }
+
+    /// Read the serial number
+    pub async fn serial_number(&self) -> Result<String> {
+        let raw: [u16; 8] = self.read_array(RegAddr::SerialNumber as u16).await?;
+        Ok(decode_string(&raw))
+    }
+
+    /// Read the manufacturer name the BMS reports, e.g. to tell Renogy packs from rebrands
+    ///
+    /// Read from the 10 registers at 0x140c as up to 20 ASCII characters, two per register
+    /// high byte first, ending at the first NUL. Models which don't populate the block read as
+    /// an empty string, and those without it answer with an exception, see
+    /// `Error::is_unsupported`.
+    pub async fn vendor_id(&self) -> Result<String> {
+        let raw: [u16; 10] = self.read_array(RegAddr::ManufacturerName as u16).await?;
+        Ok(decode_string(&raw))
+    }
+
+    /// Read the static information identifying the battery
+    ///
+    /// None of it changes while the battery is running, so it is only read from the battery the
+    /// first time, and a copy of that is returned from then on. Use `refresh_info` to re-read it.
+    pub async fn read_info(&self) -> Result<BatteryInfo> {
+        let cached = self.shared().info.clone();
+        match cached {
+            Some(info) => Ok(info),
+            None => self.refresh_info().await,
+        }
+    }
+
+    /// Re-read the static information identifying the battery, replacing the cached copy
+    pub async fn refresh_info(&self) -> Result<BatteryInfo> {
+        let info = BatteryInfo {
+            model: self.model().await?,
+            serial_number: self.serial_number().await?,
+            vendor_id: optional(self.vendor_id().await)?.unwrap_or_default(),
+        };
+        self.shared().info = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Read and decode all of the BMS status registers in one transaction
+    pub async fn status(&self) -> Result<BatteryStatus> {
+        Ok(BatteryStatus::from_registers(self.read_array(RegAddr::Status1 as u16).await?))
+    }
+
+    /// Read the status registers as named conditions with severities
+    pub async fn conditions(&self) -> Result<Vec<Condition>> {
+        Ok(self.status().await?.conditions())
+    }
+
+    /// Get the time until the battery is empty at the present discharge rate
+    ///
+    /// Returns `None` unless the battery is discharging (see `DEFAULT_FLOW_DEADBAND`). Reads
+    /// `RegisterMap::time_to_empty` if set, otherwise estimates it as remaining_charge / current.
+    pub async fn time_to_empty(&self) -> Result<Option<TimeEstimate>> {
+        let current = self.current().await?;
+        if current >= -DEFAULT_FLOW_DEADBAND {
+            return Ok(None);
+        }
+        if let Some(addr) = self.map.time_to_empty {
+            let minutes = self.read_u16(addr).await?;
+            return Ok(Some(TimeEstimate { duration: Duration::from_secs(minutes as u64 * 60), estimated: false }));
+        }
+        let hours = self.remaining_charge().await? / -current;
+        Ok(Some(TimeEstimate { duration: Duration::from_secs_f64(hours.max(0.0) * 3600.0), estimated: true }))
+    }
+
+    /// Get the time until the battery is full at the present charge rate
+    ///
+    /// Returns `None` unless the battery is charging (see `DEFAULT_FLOW_DEADBAND`). Reads
+    /// `RegisterMap::time_to_full` if set, otherwise estimates it as
+    /// (capacity - remaining_charge) / current. The estimate assumes a constant current, so it
+    /// runs short once the charger tapers off in the absorption stage.
+    pub async fn time_to_full(&self) -> Result<Option<TimeEstimate>> {
+        let current = self.current().await?;
+        if current <= DEFAULT_FLOW_DEADBAND {
+            return Ok(None);
+        }
+        if let Some(addr) = self.map.time_to_full {
+            let minutes = self.read_u16(addr).await?;
+            return Ok(Some(TimeEstimate { duration: Duration::from_secs(minutes as u64 * 60), estimated: false }));
+        }
+        let missing = self.capacity().await? - self.remaining_charge().await?;
+        let hours = missing / current;
+        Ok(Some(TimeEstimate { duration: Duration::from_secs_f64(hours.max(0.0) * 3600.0), estimated: true }))
+    }
+
+    /// Read the configured over/under voltage protection thresholds
+    pub async fn protection_thresholds(&self) -> Result<ProtectionConfig> {
+        // The thresholds live in one block of config registers, so read it in a single transaction
+        let base = RegAddr::CellOverVoltageLimit as u16;
+        let size = RegAddr::PackUnderVoltageLimit as u16 - base + 1;
+        let raw = self.read_register(base, size).await?;
+        let reg = |addr: RegAddr| block_word(&raw, base, addr as u16).map(|v| v as f64 * 0.1);
+        Ok(ProtectionConfig {
+            cell_over_voltage: reg(RegAddr::CellOverVoltageLimit)?,
+            cell_under_voltage: reg(RegAddr::CellUnderVoltageLimit)?,
+            pack_over_voltage: reg(RegAddr::PackOverVoltageLimit)?,
+            pack_under_voltage: reg(RegAddr::PackUnderVoltageLimit)?,
+        })
+    }
+
+    /// Read the temperature range the BMS allows charging and discharging in
+    ///
+    /// The limits are the over and under temperature protection thresholds at 0x1454 and 0x1457
+    /// (charge) and 0x145f and 0x1462 (discharge), encoded like the cell temperatures. They are
+    /// read in one transaction. Batteries without them answer with an exception, see
+    /// `Error::is_unsupported`.
+    pub async fn temperature_limits(&self) -> Result<TempLimits> {
+        const SPAN: usize = (RegAddr::DischargeUnderTempLimit as u16 - RegAddr::ChargeOverTempLimit as u16 + 1) as usize;
+        let base = RegAddr::ChargeOverTempLimit as u16;
+        let block: [u16; SPAN] = self.read_array(base).await?;
+        let temp = |addr: RegAddr| block_word(&block, base, addr as u16).map(|v| self.map.temperature_encoding.decode(v));
+        Ok(TempLimits {
+            charge_min: temp(RegAddr::ChargeUnderTempLimit)?,
+            charge_max: temp(RegAddr::ChargeOverTempLimit)?,
+            discharge_min: temp(RegAddr::DischargeUnderTempLimit)?,
+            discharge_max: temp(RegAddr::DischargeOverTempLimit)?,
+        })
+    }
+
+    /// Write new over/under voltage protection thresholds to the BMS
+    #[cfg(feature = "write")]
+    pub async fn set_protection_thresholds(&self, config: &ProtectionConfig) -> Result<()> {
+        let encode = |v: f64| (v / 0.1).round() as u16;
+        self.write_register(RegAddr::CellOverVoltageLimit as u16, encode(config.cell_over_voltage)).await?;
+        self.write_register(RegAddr::CellUnderVoltageLimit as u16, encode(config.cell_under_voltage)).await?;
+        self.write_register(RegAddr::PackOverVoltageLimit as u16, encode(config.pack_over_voltage)).await?;
+        self.write_register(RegAddr::PackUnderVoltageLimit as u16, encode(config.pack_under_voltage)).await
+    }
+
+    /// Read every known register and check whether its value is plausible
+    ///
+    /// A diagnostic for bringing up a new model: it shows which registers the battery has, and
+    /// which hold something other than what this crate expects.
+    pub async fn probe_registers(&self) -> Vec<RegisterProbe> {
+        let mut probes = Vec::with_capacity(RegAddr::ALL.len());
+        for reg in RegAddr::ALL {
+            let probe = match self.read_register(reg as u16, reg.size()).await {
+                Ok(raw) => {
+                    let status = if self.is_plausible(reg, &raw) { ProbeStatus::Ok } else { ProbeStatus::Implausible };
+                    RegisterProbe { reg, raw: Some(raw), status }
+                }
+                Err(e) if e.is_unsupported() => RegisterProbe { reg, raw: None, status: ProbeStatus::Unsupported },
+                Err(e) => RegisterProbe { reg, raw: None, status: ProbeStatus::Failed(e) },
+            };
+            probes.push(probe);
+        }
+        probes
+    }
+
+    /// Cross-check readings to see whether the register map fits this battery
+    ///
+    /// Firmware which moved a register can return plausible looking but wrong values, so this
+    /// compares values which must agree with each other: the pack voltage against the sum of
+    /// the cells (4 cell packs only), the remaining charge against the capacity, and the current
+    /// and temperatures against what a LiFePO4 pack can physically report. Worth running once
+    /// at startup. Read failures are returned as errors, not mismatches.
+    pub async fn validate_map(&self) -> Result<MapValidation> {
+        let mut mismatches = Vec::new();
+        let mut mismatch = |field, reason: String| mismatches.push(MapMismatch { field, reason });
+
+        let layout = self.sensor_layout().await?;
+        let voltage = self.voltage().await?;
+        let cells = self.cell_voltages_4().await?;
+        if layout.cell_count == 4 {
+            let sum: f64 = cells.iter().sum();
+            if (voltage - sum).abs() > 0.5 {
+                mismatch(Field::Voltage, format!("pack reads {:.1} V but the cells sum to {:.1} V", voltage, sum));
+            }
+        }
+        if let Some(v) = cells.iter().find(|v| !(2.0..=4.0).contains(*v)) {
+            mismatch(Field::CellVoltages, format!("cell voltage {:.1} V is outside 2.0-4.0 V", v));
+        }
+
+        let remaining = self.remaining_charge().await?;
+        let capacity = self.capacity().await?;
+        if remaining > capacity * 1.05 {
+            mismatch(
+                Field::RemainingCharge,
+                format!("remaining charge {:.1} Ah exceeds the capacity {:.1} Ah", remaining, capacity),
+            );
+        }
+
+        let current = self.current().await?;
+        if current.abs() > 300.0 {
+            mismatch(Field::Current, format!("current {:.2} A is beyond any LiFePO4 pack", current));
+        }
+
+        let temps = self.cell_temps_4().await?;
+        if let Some(t) = temps.iter().find(|t| !(-40.0..=100.0).contains(*t)) {
+            mismatch(Field::CellTemps, format!("cell temperature {:.1} deg C is outside -40-100 deg C", t));
+        }
+
+        Ok(MapValidation { mismatches })
+    }
+
+    /// Check a register value is in the range expected from a LiFePO4 battery
+    fn is_plausible(&self, reg: RegAddr, raw: &[u16]) -> bool {
+        let word = raw.first().copied().unwrap_or_default();
+        let long = raw.iter().fold(0u32, |acc, w| (acc << 16) | *w as u32);
+        let scales = self.map.scales;
+        match reg {
+            RegAddr::Current => (word as i16 as f64 * scales.current).abs() <= 300.0,
+            RegAddr::Voltage => (5.0..=70.0).contains(&(word as f64 * scales.voltage)),
+            RegAddr::PackOverVoltageLimit | RegAddr::PackUnderVoltageLimit | RegAddr::ChargeVoltageLimit => {
+                (5.0..=70.0).contains(&(word as f64 * 0.1))
+            }
+            RegAddr::RemainingCharge | RegAddr::Capacity => long as f64 * scales.charge <= 2000.0,
+            RegAddr::CycleNumber => word <= 20000,
+            RegAddr::ChargeCurrentLimit | RegAddr::DischargeCurrentLimit => word as f64 * scales.current <= 1000.0,
+            RegAddr::CellCount => (1..=16).contains(&word),
+            RegAddr::TempCount => word <= 16,
+            RegAddr::CellVoltage1 | RegAddr::CellVoltage2 | RegAddr::CellVoltage3 | RegAddr::CellVoltage4 => {
+                (2.0..=4.0).contains(&(word as f64 * scales.cell_voltage))
+            }
+            RegAddr::CellOverVoltageLimit | RegAddr::CellUnderVoltageLimit => (2.0..=4.0).contains(&(word as f64 * 0.1)),
+            RegAddr::CellTemp1
+            | RegAddr::CellTemp2
+            | RegAddr::CellTemp3
+            | RegAddr::CellTemp4
+            | RegAddr::ChargeOverTempLimit
+            | RegAddr::ChargeUnderTempLimit
+            | RegAddr::DischargeOverTempLimit
+            | RegAddr::DischargeUnderTempLimit => {
+                (-40.0..=100.0).contains(&self.map.temperature_encoding.decode(word))
+            }
+            RegAddr::HeaterLevel => word <= 255,
+            RegAddr::Status1 => true,
+            RegAddr::SerialNumber | RegAddr::Model | RegAddr::ManufacturerName => {
+                let string = decode_string(raw);
+                !string.is_empty() && string.chars().all(|c| c.is_ascii_graphic())
+            }
+        }
+    }
+
+    pub async fn test(&self) {
+        let mut port = self.port.lock().await;
+        port.set_slave(240);
+
+    }
+
+    pub async fn read_all(&self) -> Result<BatteryState> {
+        let state = self.read_state().await?;
+        if self.check_consistency && !state.charge_consistent() {
+            return Err(Error::InconsistentState {
+                remaining_charge: state.remaining_charge,
+                capacity: state.capacity,
+            });
+        }
+        self.shared().last_state = Some((self.clock.now(), state));
+        Ok(state)
+    }
+
+    /// Read all battery stats into an existing `state`
+    ///
+    /// Reads go through fixed size buffers, so apart from tokio-modbus' own response buffers a
+    /// polling loop built on this doesn't allocate. `state` is left untouched if the read fails.
+    pub async fn read_all_into(&self, state: &mut BatteryState) -> Result<()> {
+        *state = self.read_all().await?;
+        Ok(())
+    }
+
+    /// Read the values for a Home Assistant battery sensor set, in three transactions
+    pub async fn read_ha_bundle(&self) -> Result<HaBundle> {
+        // Current, voltage, remaining charge and capacity are consecutive
+        let base = RegAddr::Current as u16;
+        let block: [u16; 6] = self.read_array(base).await?;
+        let word = |addr: RegAddr| block_word(&block, base, addr as u16);
+        let long = |addr: RegAddr| -> Result<u32> {
+            Ok(((word(addr)? as u32) << 16) | block_word(&block, base, addr as u16 + 1)? as u32)
+        };
+        let mut raw_current = word(RegAddr::Current)? as i16;
+        if self.map.swap_current_bytes {
+            raw_current = raw_current.swap_bytes();
+        }
+        let scales = self.map.scales;
+        let current = raw_current as f64 * scales.current;
+        let voltage = word(RegAddr::Voltage)? as f64 * scales.voltage;
+        let remaining = long(RegAddr::RemainingCharge)? as f64 * scales.charge;
+        let capacity = long(RegAddr::Capacity)? as f64 * scales.charge;
+
+        let temps = self.cell_temps_4().await?;
+        let status = self.status().await?;
+        let status = if status.protection_active() {
+            "protection"
+        } else if status.warning_active() {
+            "warning"
+        } else if status.charging {
+            "charging"
+        } else if status.discharging {
+            "discharging"
+        } else {
+            "idle"
+        };
+        Ok(HaBundle {
+            voltage,
+            current,
+            power: voltage * current,
+            battery: (remaining / capacity * 100.0).clamp(0.0, 100.0),
+            temperature: temps.iter().sum::<f64>() / temps.len() as f64,
+            status,
+        })
+    }
+
+    /// Read all battery stats, unless the port is in use
+    ///
+    /// Returns `Ok(None)` immediately if another task is using the port, instead of waiting for
+    /// it. Otherwise the port is held for the whole read, so the returned state is read in one
+    /// go. For latency sensitive loops which would rather skip a cycle than stall; expect
+    /// frequent `None`s if several tasks share the bus.
+    pub async fn try_read_all(&self) -> Result<Option<BatteryState>> {
+        let guard = match &self.port {
+            PortSource::Shared(port) => port.clone().try_lock_owned().ok(),
+            #[cfg(feature = "tcp")]
+            PortSource::Pool(pool) => pool.try_lock_owned(),
+            PortSource::Held(_) => return self.read_all().await.map(Some),
+        };
+        let Some(guard) = guard else {
+            return Ok(None);
+        };
+        let held = Battery { port: PortSource::Held(Arc::new(Mutex::new(guard))), ..self.clone() };
+        held.read_all().await.map(Some)
+    }
+
+    async fn read_state(&self) -> Result<BatteryState> {
+        let (cells, temps) = self.read_cells_and_temps().await?;
+        let [cell_voltage_1, cell_voltage_2, cell_voltage_3, cell_voltage_4] = cells;
+        let [cell_temp_1, cell_temp_2, cell_temp_3, cell_temp_4] = temps;
+        Ok(BatteryState {
+            current: self.current().await?,
+            voltage: self.voltage().await?,
+            remaining_charge: self.remaining_charge().await?,
+            capacity: self.capacity().await?,
+            cell_voltage_1,
+            cell_voltage_2,
+            cell_voltage_3,
+            cell_voltage_4,
+            cycle_number: self.cycle_number().await?,
+            cell_temp_1,
+            cell_temp_2,
+            cell_temp_3,
+            cell_temp_4,
+            heater_level: self.heater_level().await?,
+            status: self.status().await?,
+        })
+    }
+
+    /// Read only the requested fields, in the order given
+    ///
+    /// This avoids spending bus time on registers that aren't needed every poll. Put the fields
+    /// that should be freshest first.
+    pub async fn read_fields(&self, fields: &[Field]) -> Result<PartialBatteryState> {
+        let mut state = PartialBatteryState::default();
+        for field in fields {
+            match field {
+                Field::Current => state.current = Some(self.current().await?),
+                Field::Voltage => state.voltage = Some(self.voltage().await?),
+                Field::RemainingCharge => state.remaining_charge = Some(self.remaining_charge().await?),
+                Field::Capacity => state.capacity = Some(self.capacity().await?),
+                Field::CycleNumber => state.cycle_number = Some(self.cycle_number().await?),
+                Field::CellVoltages => state.cell_voltages = Some(self.cell_voltages_4().await?),
+                Field::CellTemps => state.cell_temps = Some(self.cell_temps_4().await?),
+                Field::HeaterLevel => state.heater_level = Some(self.heater_level().await?),
+                Field::Status => state.status = Some(self.status().await?),
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// Find the addresses of batteries responding on a bus
+///
+/// Each address is probed with a single register read which must be answered within
+/// `probe_timeout`. Most addresses on a bus are unoccupied, so this should be short (e.g. 50ms)
+/// to keep a full 1-247 sweep quick. If `deadline` passes before all addresses are probed, the
+/// addresses found so far are returned. An exception response counts as a device, except the
+/// gateway exceptions with which a Modbus TCP gateway reports that nothing answered.
+pub async fn scan(
+    port: Arc<Mutex<Port>>,
+    addrs: impl IntoIterator<Item = u8>,
+    probe_timeout: Duration,
+    deadline: Duration,
+) -> Vec<u8> {
+    let deadline = tokio::time::Instant::now() + deadline;
+    let mut found = Vec::new();
+    for addr in addrs {
+        let battery = Battery::new(port.clone(), addr);
+        let policy = ReadPolicy { timeout: probe_timeout, ..ReadPolicy::default() };
+        let mut buf = [0];
+        let probe = battery.read_register_into_with_policy(RegAddr::Voltage as u16, &mut buf, &policy);
+        match tokio::time::timeout_at(deadline, probe).await {
+            // A gateway answers for an absent device with an exception of its own
+            Ok(Err(Error::Exception(Exception::GatewayTargetDevice | Exception::GatewayPathUnavailable))) => (),
+            // Any other exception response still means there is a device at this address
+            Ok(Ok(_)) | Ok(Err(Error::Exception(_))) => found.push(addr),
+            Ok(Err(_)) => (),
+            Err(_) => break,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBattery;
+
+    fn battery(mock: &MockBattery) -> Battery {
+        Battery::new(Arc::new(Mutex::new(mock.port())), 1)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {}, got {}", expected, actual);
+    }
+
+    #[tokio::test]
+    async fn decodes_each_accessor() {
+        let mock = MockBattery::new();
+        let battery = battery(&mock);
+
+        mock.set(RegAddr::Current as u16, 0xff9c);
+        assert_close(battery.current().await.unwrap(), -1.0);
+        mock.set(RegAddr::Current as u16, 0x01f4);
+        assert_close(battery.current().await.unwrap(), 5.0);
+
+        mock.set(RegAddr::Voltage as u16, 0x0085);
+        assert_close(battery.voltage().await.unwrap(), 13.3);
+
+        mock.set_block(RegAddr::RemainingCharge as u16, &[0x0001, 0x86a0]);
+        assert_close(battery.remaining_charge().await.unwrap(), 100.0);
+        mock.set_block(RegAddr::Capacity as u16, &[0x0001, 0x86a0]);
+        assert_close(battery.capacity().await.unwrap(), 100.0);
+
+        mock.set(RegAddr::CycleNumber as u16, 12);
+        assert_eq!(battery.cycle_number().await.unwrap(), 12);
+
+        mock.set_block(RegAddr::CellVoltage1 as u16, &[0x0021, 0x0020, 0x001f, 0x0022]);
+        assert_close(battery.cell_voltage_1().await.unwrap(), 3.3);
+        assert_close(battery.cell_voltage_2().await.unwrap(), 3.2);
+        assert_close(battery.cell_voltage_3().await.unwrap(), 3.1);
+        assert_close(battery.cell_voltage_4().await.unwrap(), 3.4);
+
+        mock.set_block(RegAddr::CellTemp1 as u16, &[0xff9c, 0x00fa, 0x0000, 0x0001]);
+        assert_close(battery.cell_temp_1().await.unwrap(), -10.0);
+        assert_close(battery.cell_temp_2().await.unwrap(), 25.0);
+        assert_close(battery.cell_temp_3().await.unwrap(), 0.0);
+        assert_close(battery.cell_temp_4().await.unwrap(), 0.1);
+
+        mock.set(RegAddr::HeaterLevel as u16, 0x0080);
+        assert_close(battery.heater_level().await.unwrap(), 128.0 * 100.0 / 255.0);
+    }
+
+    #[tokio::test]
+    async fn decodes_raw_words() {
+        let mock = MockBattery::new();
+        let battery = battery(&mock);
+        mock.set(0x1000, 0xff9c);
+        assert_eq!(battery.read_u16(0x1000).await.unwrap(), 0xff9c);
+        assert_eq!(battery.read_i16(0x1000).await.unwrap(), -100);
+        mock.set_block(0x1001, &[0x0001, 0x86a0]);
+        assert_eq!(battery.read_u32(0x1001).await.unwrap(), 100_000);
+    }
+
+    #[tokio::test]
+    async fn applies_default_scales() {
+        let mock = MockBattery::new();
+        let map = RegisterMap { charge_current: Some(0x2000), discharge_current: Some(0x2001), ..Default::default() };
+        let battery = battery(&mock).with_register_map(map);
+        mock.set_block(RegAddr::Current as u16, &[1234, 132, 0, 50000, 1, 34464]);
+        mock.set_block(0x2000, &[1234, 250]);
+        assert_close(battery.current().await.unwrap(), 12.34);
+        assert_close(battery.voltage().await.unwrap(), 13.2);
+        assert_close(battery.remaining_charge().await.unwrap(), 50.0);
+        assert_close(battery.capacity().await.unwrap(), 100.0);
+        assert_close(battery.charge_current().await.unwrap(), 12.34);
+        assert_close(battery.discharge_current().await.unwrap(), 2.5);
+    }
+
+    #[tokio::test]
+    async fn applies_model_scales() {
+        let mock = MockBattery::new();
+        let scales = Scales { current: 0.001, voltage: 0.01, charge: 0.01, cell_voltage: 0.001 };
+        let map = RegisterMap { scales, charge_current: Some(0x2000), discharge_current: Some(0x2001), ..Default::default() };
+        let battery = battery(&mock).with_register_map(map);
+        mock.set_block(RegAddr::Current as u16, &[1234, 1320, 0, 5000, 0, 10000]);
+        mock.set_block(0x2000, &[1234, 250]);
+        mock.set(RegAddr::CellVoltage1 as u16, 3300);
+        mock.set_block(RegAddr::ChargeCurrentLimit as u16, &[50000, 25000]);
+        assert_close(battery.current().await.unwrap(), 1.234);
+        assert_close(battery.voltage().await.unwrap(), 13.2);
+        assert_close(battery.remaining_charge().await.unwrap(), 50.0);
+        assert_close(battery.capacity().await.unwrap(), 100.0);
+        assert_close(battery.cell_voltage_1().await.unwrap(), 3.3);
+        let limits = battery.active_limits().await.unwrap();
+        assert_close(limits.charge_limit, 50.0);
+        assert_close(limits.discharge_limit, 25.0);
+        // The dedicated registers and the fallback through current() must agree on units
+        assert_close(battery.charge_current().await.unwrap(), 1.234);
+        assert_close(battery.discharge_current().await.unwrap(), 0.25);
+        let fallback = battery.clone().with_register_map(RegisterMap { scales, ..Default::default() });
+        assert_close(fallback.charge_current().await.unwrap(), 1.234);
+        assert_close(fallback.discharge_current().await.unwrap(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn finds_extremes_beyond_cell_4() {
+        let mock = MockBattery::new();
+        let battery = battery(&mock);
+        let mut cells = [33; 16];
+        cells[2] = 31;
+        cells[13] = 36;
+        mock.set(RegAddr::CellCount as u16, 16);
+        mock.set_block(RegAddr::CellVoltage1 as u16, &cells);
+        let extremes = battery.cell_extremes().await.unwrap();
+        assert_eq!((extremes.max_idx, extremes.min_idx), (13, 2));
+        assert_close(extremes.max_v, 3.6);
+        assert_close(extremes.min_v, 3.1);
+
+        mock.set(RegAddr::CellCount as u16, 0);
+        assert!(matches!(battery.cell_extremes().await, Err(Error::InvalidResponse { .. })));
+    }
+
+    #[tokio::test]
+    async fn splits_block_reads_across_tables() {
+        let mock = MockBattery::rbt100();
+        // The holding table's stale voltage must never be read once voltage is an input register
+        mock.set(RegAddr::Voltage as u16, 999);
+        mock.set_input(RegAddr::Voltage as u16, 133);
+        let map = RegisterMap { input_registers: vec![RegAddr::Voltage], ..Default::default() };
+        let battery = battery(&mock).with_register_map(map);
+        assert_close(battery.voltage().await.unwrap(), 13.3);
+        let (voltage, current) = battery.read_vi().await.unwrap();
+        assert_close(voltage, 13.3);
+        assert_close(current, 5.0);
+        assert_close(battery.read_ha_bundle().await.unwrap().voltage, 13.3);
+    }
+
+    /// A gateway with one device behind it, which answers for every other address itself
+    #[derive(Debug)]
+    struct GatewayClient {
+        slave: u8,
+        present: u8,
+        unreachable: Exception,
+    }
+
+    impl SlaveContext for GatewayClient {
+        fn set_slave(&mut self, slave: Slave) {
+            self.slave = slave.0;
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl tokio_modbus::client::Client for GatewayClient {
+        async fn call(&mut self, request: Request<'_>) -> std::io::Result<Response> {
+            match request {
+                Request::ReadHoldingRegisters(_, count) if self.slave == self.present => {
+                    Ok(Response::ReadHoldingRegisters(vec![132; count as usize]))
+                }
+                _ => Err(std::io::Error::other(self.unreachable)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_skips_gateway_exceptions() {
+        for unreachable in [Exception::GatewayTargetDevice, Exception::GatewayPathUnavailable] {
+            let client: Box<dyn tokio_modbus::client::Client> = Box::new(GatewayClient { slave: 0, present: 3, unreachable });
+            let port = Arc::new(Mutex::new(Port::from_context(Context::from(client))));
+            let found = scan(port, 1..=5, Duration::from_millis(50), Duration::from_secs(5)).await;
+            assert_eq!(found, vec![3]);
+        }
+    }
+
+    #[cfg(feature = "write")]
+    #[tokio::test]
+    async fn dry_run_records_writes_without_sending_them() {
+        let mock = MockBattery::rbt100();
+        let battery = battery(&mock).with_dry_run(true);
+        battery.write_register(0x1450, 36).await.unwrap();
+        battery.write_registers(0x1453, &[25, 26]).await.unwrap();
+        battery.write_register_verified(0x1454, 7).await.unwrap();
+        assert_eq!(mock.get(0x1450), None);
+        assert_eq!(mock.get(0x1453), None);
+        assert_eq!(
+            battery.take_suppressed_writes(),
+            vec![
+                SuppressedWrite { addr: 0x1450, values: vec![36] },
+                SuppressedWrite { addr: 0x1453, values: vec![25, 26] },
+                SuppressedWrite { addr: 0x1454, values: vec![7] },
+            ]
+        );
+        assert!(battery.take_suppressed_writes().is_empty());
+        assert_close(battery.voltage().await.unwrap(), 13.2);
+    }
+
+    #[tokio::test]
+    async fn flags_charge_beyond_capacity() {
+        let mock = MockBattery::rbt100();
+        // 130 Ah remaining of a 100 Ah capacity
+        mock.set_block(RegAddr::RemainingCharge as u16, &[0x0001, 0xfbd0]);
+        let state = battery(&mock).read_all().await.unwrap();
+        assert_close(state.remaining_charge, 130.0);
+        assert_close(state.state_of_charge(), 100.0);
+        assert!(!state.charge_consistent());
+
+        match battery(&mock).with_consistency_check(true).read_all().await {
+            Err(Error::InconsistentState { remaining_charge, capacity }) => {
+                assert_close(remaining_charge, 130.0);
+                assert_close(capacity, 100.0);
+            }
+            other => panic!("expected an inconsistent state, got {:?}", other),
+        }
+
+        // Just over capacity is normal right after a full charge
+        mock.set_block(RegAddr::RemainingCharge as u16, &[0x0001, 0x9a28]);
+        assert!(battery(&mock).with_consistency_check(true).read_all().await.unwrap().charge_consistent());
+    }
+
+    #[cfg(feature = "write")]
+    #[tokio::test]
+    async fn detects_ignored_writes() {
+        let mock = MockBattery::rbt100();
+        let battery = battery(&mock);
+        mock.set(0x1450, 36);
+        battery.write_register_verified(0x1450, 37).await.unwrap();
+        assert_eq!(mock.get(0x1450), Some(37));
+
+        mock.set_ignore_writes(true);
+        match battery.write_register_verified(0x1450, 38).await {
+            Err(Error::WriteVerificationFailed { addr, written, read }) => {
+                assert_eq!((addr, written, read), (0x1450, 38, 37));
+            }
+            other => panic!("expected a verification failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn splits_long_ranges_into_max_size_reads() {
+        let mock = MockBattery::new();
+        let values: Vec<u16> = (0..130).collect();
+        mock.set_block(0x1000, &values);
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut port = mock.port();
+        let seen = requests.clone();
+        port.set_transaction_hook(Box::new(move |t| seen.lock().unwrap().push((t.addr, t.count))));
+        let battery = Battery::new(Arc::new(Mutex::new(port)), 1);
+        assert_eq!(battery.read_range(0x1000, 130).await.unwrap(), values);
+        assert_eq!(*requests.lock().unwrap(), vec![(0x1000, 125), (0x107d, 5)]);
+    }
+
+    #[tokio::test]
+    async fn reads_full_heater_level_as_100_percent() {
+        assert_eq!(255.0 * HEATER_LEVEL_SCALE, 100.0);
+        let mock = MockBattery::new();
+        let battery = battery(&mock);
+        mock.set(RegAddr::HeaterLevel as u16, 0x00ff);
+        assert_eq!(battery.heater_level().await.unwrap(), 100.0);
+        mock.set(RegAddr::HeaterLevel as u16, 0);
+        assert_eq!(battery.heater_level().await.unwrap(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn rejects_short_responses() {
+        fn assert_invalid<T: std::fmt::Debug>(result: Result<T>) {
+            match result {
+                Err(Error::InvalidResponse { sequence: Some(_), .. }) => {}
+                other => panic!("expected an invalid response, got {:?}", other),
+            }
+        }
+
+        let mock = MockBattery::rbt100();
+        // The limits and protection thresholds aren't in the RBT100 mock
+        mock.set_block(RegAddr::ChargeVoltageLimit as u16, &[144, 0, 500, 500]);
+        mock.set_block(RegAddr::CellOverVoltageLimit as u16, &[0; 0x13]);
+        mock.set_short_responses(true);
+        let battery = battery(&mock);
+        assert_invalid(battery.current().await);
+        assert_invalid(battery.voltage().await);
+        assert_invalid(battery.remaining_charge().await);
+        assert_invalid(battery.capacity().await);
+        assert_invalid(battery.cycle_number().await);
+        assert_invalid(battery.cell_voltage_1().await);
+        assert_invalid(battery.cell_voltage_2().await);
+        assert_invalid(battery.cell_voltage_3().await);
+        assert_invalid(battery.cell_voltage_4().await);
+        assert_invalid(battery.cell_temp_1().await);
+        assert_invalid(battery.cell_temp_2().await);
+        assert_invalid(battery.cell_temp_3().await);
+        assert_invalid(battery.cell_temp_4().await);
+        assert_invalid(battery.heater_level().await);
+        assert_invalid(battery.read_vi().await);
+        assert_invalid(battery.status().await);
+        assert_invalid(battery.model().await);
+        assert_invalid(battery.serial_number().await);
+        assert_invalid(battery.read_all().await);
+        assert_invalid(battery.cell_voltages_4().await);
+        assert_invalid(battery.cell_voltages_n(4).await);
+        assert_invalid(battery.cell_temps_4().await);
+        assert_invalid(battery.read_cells_and_temps().await);
+        assert_invalid(battery.protection_thresholds().await);
+        assert_invalid(battery.temperature_limits().await);
+        assert_invalid(battery.active_limits().await);
+        assert_invalid(battery.read_ha_bundle().await);
+        assert_invalid(battery.sensor_layout().await);
+        assert_invalid(battery.target_charge_voltage().await);
+        assert_invalid(battery.vendor_id().await);
+
+        mock.set_short_responses(false);
+        assert_close(battery.voltage().await.unwrap(), 13.2);
+    }
+
+    /// The modbus RTU CRC of `frame`
+    fn crc16(frame: &[u8]) -> u16 {
+        frame.iter().fold(0xffff, |crc, byte| {
+            (0..8).fold(crc ^ *byte as u16, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xa001 } else { crc >> 1 })
+        })
+    }
+
+    /// The response PDU to a read holding registers request PDU, with every register reading `value`
+    fn read_response(request: &[u8], value: u16) -> Vec<u8> {
+        let [function, _, _, _, count] = request else {
+            panic!("unexpected request {:02x?}", request);
+        };
+        assert_eq!(*function, 0x03);
+        let mut response = vec![*function, count * 2];
+        for _ in 0..*count {
+            response.extend(value.to_be_bytes());
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn reads_over_rtu() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(256);
+        tokio::spawn(async move {
+            let mut request = [0u8; 8];
+            while server.read_exact(&mut request).await.is_ok() {
+                let (frame, crc) = request.split_at(6);
+                assert_eq!(crc, crc16(frame).to_le_bytes());
+                let (slave, pdu) = frame.split_at(1);
+                let mut response = slave.to_vec();
+                response.extend(read_response(pdu, 132));
+                response.extend(crc16(&response).to_le_bytes());
+                if server.write_all(&response).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let port = Port::from_context(rtu::attach(client));
+        let battery = Battery::new(Arc::new(Mutex::new(port)), 1);
+        assert_close(battery.voltage().await.unwrap(), 13.2);
+        assert_eq!(battery.read_register(RegAddr::Voltage as u16, 3).await.unwrap(), vec![132; 3]);
+    }
+
+    /// Start a gateway answering every read from slave 1 with 132s, on as many connections as asked
+    #[cfg(feature = "tcp")]
+    async fn spawn_gateway() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_gateway_connection(stream));
+            }
+        });
+        addr
+    }
+
+    /// Answer requests on one connection to the gateway from `spawn_gateway`
+    #[cfg(feature = "tcp")]
+    async fn serve_gateway_connection(mut stream: tokio::net::TcpStream) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut header = [0u8; 7];
+        while stream.read_exact(&mut header).await.is_ok() {
+            let [tid_hi, tid_lo, 0, 0, len_hi, len_lo, unit] = header else {
+                panic!("unexpected header {:02x?}", header);
+            };
+            assert_eq!(unit, 1);
+            let mut pdu = vec![0; u16::from_be_bytes([len_hi, len_lo]) as usize - 1];
+            stream.read_exact(&mut pdu).await.unwrap();
+            let pdu = read_response(&pdu, 132);
+            let mut response = vec![tid_hi, tid_lo, 0, 0];
+            response.extend((pdu.len() as u16 + 1).to_be_bytes());
+            response.push(unit);
+            response.extend(pdu);
+            if stream.write_all(&response).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn reads_over_tcp() {
+        let port = Port::connect_tcp(spawn_gateway().await).await.unwrap();
+        let battery = Battery::new(Arc::new(Mutex::new(port)), 1);
+        assert_close(battery.voltage().await.unwrap(), 13.2);
+        assert_eq!(battery.read_register(RegAddr::Voltage as u16, 3).await.unwrap(), vec![132; 3]);
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn pooled_batteries_are_idle_only_when_idle_on_every_connection() {
+        let pool = PortPool::connect_tcp(spawn_gateway().await, 2).await.unwrap();
+        let battery = Battery::new_pooled(pool.clone(), 1);
+        assert_eq!(pool.idle_time(1).await, Duration::MAX);
+        let (first, second) = {
+            let mut ports = pool.ports();
+            (ports.next().unwrap().clone(), ports.next().unwrap().clone())
+        };
+        {
+            // Keep the first connection busy, so the read goes over the second
+            let _busy = first.lock().await;
+            assert_close(battery.voltage().await.unwrap(), 13.2);
+        }
+        assert_eq!(first.lock().await.idle_time(1), Duration::MAX);
+        assert!(second.lock().await.idle_time(1) < WAKEUP_IDLE);
+        assert!(pool.idle_time(1).await < WAKEUP_IDLE);
+    }
+
+    #[tokio::test]
+    async fn timeouts_carry_the_transaction_sequence() {
+        // Nothing answers on the other end of the stream
+        let (client, _server) = tokio::io::duplex(256);
+        let mut port = Port::from_context(rtu::attach(client));
+        let traced = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = traced.clone();
+        port.set_transaction_hook(Box::new(move |t| seen.lock().unwrap().push(t.sequence)));
+        let port = Arc::new(Mutex::new(port));
+        let battery = Battery::new(port.clone(), 1)
+            .with_read_policy(ReadPolicy { timeout: Duration::from_millis(20), ..Default::default() });
+        for expected in 1..=2 {
+            match battery.voltage().await {
+                Err(Error::Timeout { sequence }) => assert_eq!(sequence, expected),
+                other => panic!("expected a timeout, got {:?}", other),
+            }
+        }
+        assert_eq!(*traced.lock().unwrap(), vec![1, 2]);
+        assert_eq!(port.lock().await.sequence(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_cell_counts_beyond_the_cell_registers() {
+        let mock = MockBattery::rbt100();
+        let battery = battery(&mock);
+        mock.set(RegAddr::CellCount as u16, 16);
+        assert_close(battery.computed_pack_voltage().await.unwrap(), 13.2);
+
+        // The temperature count and temperatures follow the 16 cell voltage registers
+        mock.set(RegAddr::CellCount as u16, 20);
+        assert!(matches!(battery.computed_pack_voltage().await, Err(Error::InvalidResponse { .. })));
+        assert!(matches!(battery.cell_extremes().await, Err(Error::InvalidResponse { .. })));
+        assert!(matches!(battery.cell_voltages_n(17).await, Err(Error::InvalidInput(_))));
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn polls_with_a_zero_interval() {
+        use futures_util::StreamExt;
+
+        let mock = MockBattery::rbt100();
+        let battery = battery(&mock);
+        let state = std::pin::pin!(battery.poll_stream(Duration::ZERO)).next().await.unwrap().unwrap();
+        assert_close(state.voltage, 13.2);
+        let buffered = battery.poll_stream_buffered(Duration::ZERO, 2, BufferPolicy::DropOldest);
+        let state = std::pin::pin!(buffered).next().await.unwrap().unwrap();
+        assert_close(state.voltage, 13.2);
+    }
+
+    /// A transport which panics on every request
+    #[cfg(feature = "stream")]
+    #[derive(Debug)]
+    struct PanickingClient;
+
+    #[cfg(feature = "stream")]
+    impl SlaveContext for PanickingClient {
+        fn set_slave(&mut self, _slave: Slave) {}
+    }
+
+    #[cfg(feature = "stream")]
+    #[async_trait::async_trait]
+    impl tokio_modbus::client::Client for PanickingClient {
+        async fn call(&mut self, _request: Request<'_>) -> std::io::Result<Response> {
+            panic!("transport failed");
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn buffered_stream_ends_with_its_task() {
+        use futures_util::StreamExt;
+
+        let client: Box<dyn tokio_modbus::client::Client> = Box::new(PanickingClient);
+        let port = Arc::new(Mutex::new(Port::from_context(Context::from(client))));
+        let battery = Battery::new(port, 1);
+        let buffered = battery.poll_stream_buffered(Duration::from_millis(10), 2, BufferPolicy::DropOldest);
+        let next = tokio::time::timeout(Duration::from_secs(5), std::pin::pin!(buffered).next()).await;
+        assert!(matches!(next, Ok(None)));
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn serves_around_a_silent_client() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mock = MockBattery::rbt100();
+        tokio::spawn(serve(battery(&mock), addr, Duration::from_millis(10)));
+        let silent = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 "), "unexpected response {:?}", response);
+        drop(silent);
+    }
+
+    #[test]
+    fn rejects_counters_without_a_capacity() {
+        for capacity in [0.0, -100.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(CoulombCounter::new(50.0, capacity), Err(Error::InvalidInput(_))));
+        }
+        assert!(matches!(CoulombCounter::new(f64::NAN, 100.0), Err(Error::InvalidInput(_))));
+        let mut counter = CoulombCounter::new(150.0, 100.0).unwrap();
+        assert_close(counter.soc(), 100.0);
+        counter.update(-10.0, Duration::from_secs(3600));
+        assert_close(counter.charge(), 90.0);
+    }
+
+    #[tokio::test]
+    async fn tracks_soc_only_once_the_capacity_is_positive() {
+        let mock = MockBattery::rbt100();
+        mock.set_block(RegAddr::Capacity as u16, &[0, 0]);
+        let (handle, rx) = spawn_soc_tracker(battery(&mock), 60.0, Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+        assert_close(*rx.borrow(), 60.0);
+        handle.abort();
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::prelude::*;
+
+use crate::{Port, RegAddr};
+
+/// An in-memory battery, for exercising the crate without hardware
+///
+/// Answers every slave address from one bank of registers, which is shared between clones so a
+/// test or benchmark can change values while a `Battery` reads them. Reads touching a register
+/// which hasn't been set fail with an illegal data address exception, like a real BMS. Input
+/// registers (function 0x04) are a separate table, set with `set_input`.
+#[derive(Clone, Debug, Default)]
+pub struct MockBattery {
+    registers: Arc<Mutex<BTreeMap<u16, u16>>>,
+    input_registers: Arc<Mutex<BTreeMap<u16, u16>>>,
+    ignore_writes: Arc<AtomicBool>,
+    short_responses: Arc<AtomicBool>,
+}
+
+impl MockBattery {
+    /// A battery with no registers set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A battery populated with plausible readings for a half charged RBT100LFP12S
+    pub fn rbt100() -> Self {
+        let mock = Self::new();
+        // 5.00 A charging at 13.2 V, 50 of 100 Ah
+        mock.set_block(RegAddr::Current as u16, &[500, 132, 0, 50000, 1, 34464, 12]);
+        mock.set_block(RegAddr::CellCount as u16, &[4, 33, 33, 33, 33]);
+        // Cells 5-16 are absent and read as 0
+        mock.set_block(RegAddr::CellVoltage4 as u16 + 1, &[0; 12]);
+        // 25.0 deg C
+        mock.set_block(RegAddr::TempCount as u16, &[4, 250, 250, 250, 250]);
+        mock.set(RegAddr::HeaterLevel as u16, 0);
+        mock.set_block(RegAddr::Status1 as u16, &[0; 4]);
+        mock.set_string(RegAddr::SerialNumber as u16, 8, "MOCK0001");
+        mock.set_string(RegAddr::Model as u16, 8, "RBT100LFP12S");
+        mock.set_string(RegAddr::ManufacturerName as u16, 10, "RENOGY");
+        mock
+    }
+
+    /// Set one register
+    pub fn set(&self, addr: u16, value: u16) {
+        self.registers().insert(addr, value);
+    }
+
+    /// Set one input register
+    pub fn set_input(&self, addr: u16, value: u16) {
+        self.input_registers.lock().unwrap_or_else(|e| e.into_inner()).insert(addr, value);
+    }
+
+    /// Set consecutive registers starting at `addr`
+    pub fn set_block(&self, addr: u16, values: &[u16]) {
+        let mut registers = self.registers();
+        for (addr, value) in (addr..).zip(values) {
+            registers.insert(addr, *value);
+        }
+    }
+
+    /// Set `size` registers starting at `addr` to an ASCII string, two characters per register
+    /// high byte first, padded with NULs
+    pub fn set_string(&self, addr: u16, size: u16, value: &str) {
+        let mut bytes = value.bytes();
+        let words: Vec<u16> = (0..size)
+            .map(|_| u16::from_be_bytes([bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)]))
+            .collect();
+        self.set_block(addr, &words);
+    }
+
+    /// Acknowledge writes without storing them, like a BMS silently rejecting a value
+    pub fn set_ignore_writes(&self, ignore: bool) {
+        self.ignore_writes.store(ignore, Ordering::Relaxed);
+    }
+
+    /// Answer reads with one register fewer than requested, like firmware truncating a response
+    pub fn set_short_responses(&self, short: bool) {
+        self.short_responses.store(short, Ordering::Relaxed);
+    }
+
+    /// The current value of a register
+    pub fn get(&self, addr: u16) -> Option<u16> {
+        self.registers().get(&addr).copied()
+    }
+
+    /// A port on which every slave address talks to this battery
+    pub fn port(&self) -> Port {
+        let client: Box<dyn Client> = Box::new(MockClient { registers: self.clone() });
+        Port::from_context(Context::from(client))
+    }
+
+    fn registers(&self) -> std::sync::MutexGuard<'_, BTreeMap<u16, u16>> {
+        self.registers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read(&self, addr: u16, count: u16) -> std::io::Result<Vec<u16>> {
+        self.read_table(&self.registers(), addr, count)
+    }
+
+    fn read_input(&self, addr: u16, count: u16) -> std::io::Result<Vec<u16>> {
+        self.read_table(&self.input_registers.lock().unwrap_or_else(|e| e.into_inner()), addr, count)
+    }
+
+    fn read_table(&self, registers: &BTreeMap<u16, u16>, addr: u16, count: u16) -> std::io::Result<Vec<u16>> {
+        let mut words = (0..count)
+            .map(|i| addr.checked_add(i).and_then(|addr| registers.get(&addr).copied()))
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| std::io::Error::other(Exception::IllegalDataAddress))?;
+        if self.short_responses.load(Ordering::Relaxed) {
+            words.pop();
+        }
+        Ok(words)
+    }
+}
+
+#[derive(Debug)]
+struct MockClient {
+    registers: MockBattery,
+}
+
+impl SlaveContext for MockClient {
+    fn set_slave(&mut self, _slave: Slave) {}
+}
+
+#[async_trait::async_trait]
+impl Client for MockClient {
+    async fn call(&mut self, request: Request<'_>) -> std::io::Result<Response> {
+        match request {
+            Request::ReadHoldingRegisters(addr, count) => {
+                self.registers.read(addr, count).map(Response::ReadHoldingRegisters)
+            }
+            Request::ReadInputRegisters(addr, count) => {
+                self.registers.read_input(addr, count).map(Response::ReadInputRegisters)
+            }
+            Request::WriteSingleRegister(addr, value) => {
+                if !self.registers.ignore_writes.load(Ordering::Relaxed) {
+                    self.registers.set(addr, value);
+                }
+                Ok(Response::WriteSingleRegister(addr, value))
+            }
+            Request::WriteMultipleRegisters(addr, values) => {
+                if !self.registers.ignore_writes.load(Ordering::Relaxed) {
+                    self.registers.set_block(addr, &values);
+                }
+                Ok(Response::WriteMultipleRegisters(addr, values.len() as u16))
+            }
+            _ => Err(std::io::Error::other(Exception::IllegalFunction)),
+        }
+    }
+}
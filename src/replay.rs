@@ -0,0 +1,45 @@
+use crate::{Battery, BatteryState, Error, MockBattery, Result};
+
+impl MockBattery {
+    /// A battery holding the registers seen in a transaction log, see `Transaction`'s `Display`
+    ///
+    /// Successful reads from and writes to `slave` are applied in log order, so each register
+    /// ends up with the last value seen. Other slaves, failed transactions and blank lines are
+    /// skipped.
+    pub fn from_log(log: &str, slave: u8) -> Result<Self> {
+        let mock = MockBattery::new();
+        for (number, line) in log.lines().enumerate() {
+            let bad_line = || Error::InvalidInput(format!("line {}: expected a transaction, got {:?}", number + 1, line));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [_sequence, line_slave, _function, addr, _count, _latency, status, words @ ..] = fields.as_slice() else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Err(bad_line());
+            };
+            if line_slave.parse::<u8>().map_err(|_| bad_line())? != slave || *status != "ok" {
+                continue;
+            }
+            let addr = parse_hex(addr).ok_or_else(bad_line)?;
+            let words = words.iter().map(|w| parse_hex(w)).collect::<Option<Vec<u16>>>().ok_or_else(bad_line)?;
+            mock.set_block(addr, &words);
+        }
+        Ok(mock)
+    }
+}
+
+fn parse_hex(field: &str) -> Option<u16> {
+    u16::from_str_radix(field.strip_prefix("0x")?, 16).ok()
+}
+
+/// Decode a `BatteryState` from the registers captured in a transaction log
+///
+/// For diagnosing a problem from a user's log without the hardware: the log is loaded with
+/// `MockBattery::from_log` and read back with `Battery::read_all` through the normal decoders.
+/// Fails with an illegal data address exception if the log lacks registers `read_all` needs,
+/// e.g. if it was captured from `read_fields`.
+pub async fn replay(log: &str, slave: u8) -> Result<BatteryState> {
+    let mock = MockBattery::from_log(log, slave)?;
+    let port = std::sync::Arc::new(tokio::sync::Mutex::new(mock.port()));
+    Battery::new(port, slave).read_all().await
+}
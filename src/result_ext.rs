@@ -0,0 +1,31 @@
+use crate::Error;
+
+/// Logging helpers for results, for polling loops which report errors and carry on
+///
+/// ```no_run
+/// # async fn example(battery: renogy::Battery) {
+/// use renogy::ResultExt;
+///
+/// loop {
+///     if let Some(state) = battery.read_all().await.log_err("reading battery") {
+///         println!("{:.1} V", state.voltage);
+///     }
+/// }
+/// # }
+/// ```
+pub trait ResultExt<T> {
+    /// Log the error, if any, at error level with `context` in front, and convert to an `Option`
+    fn log_err(self, context: &str) -> Option<T>;
+}
+
+impl<T> ResultExt<T> for Result<T, Error> {
+    fn log_err(self, context: &str) -> Option<T> {
+        match self {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::error!("{}: {:?}", context, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use futures_util::{stream, Stream};
+use tokio::sync::Notify;
+
+use crate::{poll_ticker as ticker, Battery, BatteryState, Result};
+
+/// What `Battery::poll_stream_buffered` does with a new reading when its queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BufferPolicy {
+    /// Discard the oldest queued reading, so the queue holds the most recent `depth` readings
+    #[default]
+    DropOldest,
+    /// Replace the newest queued reading, so the backlog is kept and the last entry is always
+    /// the latest reading
+    Coalesce,
+}
+
+/// The queue between the polling task and the consumer of a buffered stream
+struct Buffer {
+    queue: Mutex<VecDeque<Result<BatteryState>>>,
+    ready: Notify,
+    /// Set once the polling task has stopped, including by panicking
+    closed: AtomicBool,
+}
+
+impl Buffer {
+    fn queue(&self) -> std::sync::MutexGuard<'_, VecDeque<Result<BatteryState>>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Held by the polling task, and marks the buffer closed when the task ends however it ends
+struct Producer(Weak<Buffer>);
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.0.upgrade() {
+            buffer.closed.store(true, Ordering::Release);
+            buffer.ready.notify_one();
+        }
+    }
+}
+
+/// A small xorshift PRNG for spreading reads out, which needn't be any good
+struct Jitter {
+    max: Duration,
+    state: u64,
+}
+
+impl Jitter {
+    fn new(max: Duration, seed: u64) -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as u64;
+        // The state must never be zero
+        Self { max, state: (nanos ^ seed.rotate_left(32)) | 1 }
+    }
+
+    /// A random delay between zero and `max`
+    fn next(&mut self) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.max.mul_f64((self.state >> 11) as f64 / (1u64 << 53) as f64)
+    }
+}
+
+impl Battery {
+    /// Read all battery stats every `interval`
+    ///
+    /// The first read happens immediately. Failed reads are yielded as errors and polling
+    /// continues. Reads aren't jittered, see `poll_stream_with_jitter`. Nothing is read ahead
+    /// of the consumer, so a slow consumer slows the polling; see `poll_stream_buffered` to
+    /// keep reading on schedule instead.
+    pub fn poll_stream(&self, interval: Duration) -> impl Stream<Item = Result<BatteryState>> {
+        self.poll_stream_with_jitter(interval, Duration::ZERO)
+    }
+
+    /// Read all battery stats every `interval`, each read delayed by a random up to `jitter`
+    ///
+    /// Independent pollers on one bus with the same interval otherwise keep colliding on the
+    /// port. The delays don't accumulate, so reads still average one per `interval`; keep
+    /// `jitter` below `interval`, e.g. a tenth of it. A zero `jitter` is the same as
+    /// `poll_stream`.
+    pub fn poll_stream_with_jitter(
+        &self,
+        interval: Duration,
+        jitter: Duration,
+    ) -> impl Stream<Item = Result<BatteryState>> {
+        let jitter = Jitter::new(jitter, self.addr() as u64);
+        stream::unfold((self.clone(), ticker(interval), jitter), |(battery, mut ticker, mut jitter)| async move {
+            ticker.tick().await;
+            let delay = jitter.next();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let state = battery.read_all().await;
+            Some((state, (battery, ticker, jitter)))
+        })
+    }
+
+    /// Read all battery stats every `interval` in a background task, queueing up to `depth`
+    ///
+    /// Unlike `poll_stream`, which only reads when the consumer asks for the next item, reads
+    /// here keep to `interval` however slowly the stream is consumed. When the consumer falls
+    /// `depth` readings behind, `policy` decides which reading is given up, so memory stays
+    /// bounded. A `depth` of 0 is treated as 1. Errors are queued like readings.
+    ///
+    /// The task is spawned on the current tokio runtime, and stops at its next read after the
+    /// stream is dropped. The stream ends once the queued readings are consumed if the task
+    /// stops first, e.g. because it panicked.
+    pub fn poll_stream_buffered(
+        &self,
+        interval: Duration,
+        depth: usize,
+        policy: BufferPolicy,
+    ) -> impl Stream<Item = Result<BatteryState>> {
+        let depth = depth.max(1);
+        let buffer = Arc::new(Buffer {
+            queue: Mutex::new(VecDeque::with_capacity(depth)),
+            ready: Notify::new(),
+            closed: AtomicBool::new(false),
+        });
+        let producer = Producer(Arc::downgrade(&buffer));
+        let battery = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = ticker(interval);
+            loop {
+                ticker.tick().await;
+                let state = battery.read_all().await;
+                let Some(buffer) = producer.0.upgrade() else {
+                    break;
+                };
+                let mut queue = buffer.queue();
+                if queue.len() >= depth {
+                    match policy {
+                        BufferPolicy::DropOldest => {
+                            queue.pop_front();
+                        }
+                        BufferPolicy::Coalesce => {
+                            queue.pop_back();
+                        }
+                    }
+                }
+                queue.push_back(state);
+                drop(queue);
+                buffer.ready.notify_one();
+            }
+        });
+        stream::unfold(buffer, |buffer| async move {
+            loop {
+                let next = buffer.queue().pop_front();
+                if let Some(state) = next {
+                    return Some((state, buffer));
+                }
+                if buffer.closed.load(Ordering::Acquire) {
+                    return None;
+                }
+                buffer.ready.notified().await;
+            }
+        })
+    }
+}
+
+/// Read all stats from each of `batteries` every `interval`, as one stream tagged by address
+///
+/// The batteries are read one after another each interval, so batteries sharing a bus never
+/// contend for it. Errors are yielded for the battery they occurred on, and don't stop the
+/// stream.
+pub fn merged_poll_stream(
+    batteries: Vec<Battery>,
+    interval: Duration,
+) -> impl Stream<Item = (u8, Result<BatteryState>)> {
+    stream::unfold((batteries, ticker(interval), 0), |(batteries, mut ticker, next)| async move {
+        if next == 0 {
+            ticker.tick().await;
+        }
+        let battery = batteries.get(next)?;
+        let reading = (battery.addr(), battery.read_all().await);
+        let next = (next + 1) % batteries.len();
+        Some((reading, (batteries, ticker, next)))
+    })
+}